@@ -0,0 +1,183 @@
+/*
+ * Drives the C ABI in geph4client.h end to end. It points the daemon at a
+ * broker address that refuses connections, so no network or account is
+ * needed: the tunnel just stays in the connecting state. Build and run with
+ * ffi-test/run.sh.
+ */
+#include <arpa/inet.h>
+#include <netinet/in.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/socket.h>
+#include <unistd.h>
+
+#include "../src/geph4client.h"
+
+static int failures = 0;
+static int log_lines = 0;
+
+#define CHECK(cond)                                                  \
+    do {                                                             \
+        if (!(cond)) {                                               \
+            fprintf(stderr, "FAILED %s:%d: %s\n", __FILE__, __LINE__, \
+                    #cond);                                          \
+            failures++;                                              \
+        }                                                            \
+    } while (0)
+
+static void on_log(const char *line, void *userdata) {
+    (void)line;
+    __atomic_fetch_add((int *)userdata, 1, __ATOMIC_SEQ_CST);
+}
+
+/* Finds n distinct TCP ports on localhost that are free right now. Returns 0 on success. */
+static int free_ports(int *ports, int n) {
+    int fds[8];
+    int ok = 0;
+    for (int i = 0; i < n; i++) {
+        fds[i] = socket(AF_INET, SOCK_STREAM, 0);
+        struct sockaddr_in addr;
+        memset(&addr, 0, sizeof(addr));
+        addr.sin_family = AF_INET;
+        addr.sin_addr.s_addr = htonl(INADDR_LOOPBACK);
+        socklen_t len = sizeof(addr);
+        /* every socket stays bound until all are found, so no port is handed out twice */
+        if (fds[i] < 0 || bind(fds[i], (struct sockaddr *)&addr, sizeof(addr)) != 0 ||
+            getsockname(fds[i], (struct sockaddr *)&addr, &len) != 0) {
+            ok = -1;
+            ports[i] = 0;
+        } else {
+            ports[i] = ntohs(addr.sin_port);
+        }
+    }
+    for (int i = 0; i < n; i++) {
+        if (fds[i] >= 0) close(fds[i]);
+    }
+    return ok;
+}
+
+/* Calls a stats RPC method with no parameters, returning 0 if the daemon answered true. */
+static int call_stats(int port, const char *method) {
+    int fd = socket(AF_INET, SOCK_STREAM, 0);
+    if (fd < 0) return -1;
+    struct sockaddr_in addr;
+    memset(&addr, 0, sizeof(addr));
+    addr.sin_family = AF_INET;
+    addr.sin_addr.s_addr = htonl(INADDR_LOOPBACK);
+    addr.sin_port = htons(port);
+    if (connect(fd, (struct sockaddr *)&addr, sizeof(addr)) != 0) {
+        close(fd);
+        return -1;
+    }
+    char body[128];
+    snprintf(body, sizeof(body), "{\"jsonrpc\":\"2.0\",\"method\":\"%s\",\"params\":[],\"id\":1}",
+             method);
+    char request[512];
+    snprintf(request, sizeof(request),
+             "POST / HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: application/json\r\n"
+             "Content-Length: %zu\r\nConnection: close\r\n\r\n%s",
+             strlen(body), body);
+    char response[2048];
+    size_t got = 0;
+    ssize_t n = write(fd, request, strlen(request)) < 0 ? -1 : 0;
+    while (n >= 0 && got < sizeof(response) - 1 &&
+           (n = read(fd, response + got, sizeof(response) - 1 - got)) > 0) {
+        got += n;
+    }
+    close(fd);
+    response[got] = '\0';
+    return strstr(response, "\"result\":true") != NULL ? 0 : -1;
+}
+
+static char config[1024];
+static int stats_port;
+static char socks5_name[64];
+
+/* Fills in the options with free ports, so that runs never collide with anything else listening. */
+static int make_config(void) {
+    int ports[4];
+    if (free_ports(ports, 4) != 0) return -1;
+    snprintf(socks5_name, sizeof(socks5_name), "\"socks5 127.0.0.1:%d\"", ports[0]);
+    snprintf(config, sizeof(config),
+             "{\"Connect\": {"
+             "\"socks5_listen\": \"127.0.0.1:%d\","
+             "\"http_listen\": \"127.0.0.1:%d\","
+             "\"stats_listen\": \"127.0.0.1:%d\","
+             "\"dns_listen\": \"127.0.0.1:%d\","
+             "\"common\": {\"broker_addr\": \"127.0.0.1:1\"},"
+             "\"auth\": {\"credential_cache\": \"/tmp/geph4-ffi-test\","
+             "\"auth_kind\": {\"AuthPassword\": {\"username\": \"test\", \"password\": \"test\"}}}"
+             "}}",
+             ports[0], ports[1], ports[2], ports[3]);
+    stats_port = ports[2];
+    return 0;
+}
+
+int main(void) {
+    if (make_config() != 0) {
+        fprintf(stderr, "cannot find free ports\n");
+        return 1;
+    }
+    geph4_set_log_callback(on_log, &log_lines);
+
+    /* nothing is running yet */
+    CHECK(geph4_is_connected() == -1);
+    CHECK(geph4_stats_json() == NULL);
+    CHECK(geph4_stop() == -1);
+    CHECK(geph4_last_error() != NULL);
+
+    /* bad options are rejected with an error message */
+    CHECK(geph4_start("not json") == -1);
+    CHECK(geph4_last_error() != NULL);
+    CHECK(geph4_start("{\"Connect\": {\"force_protocol\": \"sosistab\"}}") == -1);
+    CHECK(strstr(geph4_last_error(), "force_protocol") != NULL);
+    CHECK(geph4_start("{\"DebugPack\": {\"export_to\": \"/dev/null\"}}") == -1);
+
+    /* a real start, stats and stop */
+    CHECK(geph4_start(config) == 0);
+    CHECK(geph4_start(config) == -1);
+    sleep(2);
+    CHECK(geph4_is_connected() == 0);
+    char *stats = geph4_stats_json();
+    CHECK(stats != NULL);
+    if (stats) {
+        printf("stats: %s\n", stats);
+        CHECK(strstr(stats, "\"connected\":false") != NULL);
+        CHECK(strstr(stats, socks5_name) != NULL);
+        geph4_free_string(stats);
+    }
+    CHECK(geph4_stop() == 0);
+    CHECK(geph4_is_connected() == -1);
+
+    /* the daemon can be started again in the same process */
+    CHECK(geph4_start(config) == 0);
+    CHECK(geph4_stop() == 0);
+
+    /* the kill method of the stats RPC stops the daemon without geph4_stop */
+    CHECK(geph4_start(config) == 0);
+    sleep(1);
+    CHECK(call_stats(stats_port, "kill") == 0);
+    sleep(3);
+    CHECK(geph4_is_connected() == -1);
+    CHECK(geph4_stop() == -1);
+    CHECK(geph4_start(config) == 0);
+    CHECK(geph4_stop() == 0);
+
+    /* stopping right after the kill method waits for its shutdown, so starting again gets the ports */
+    CHECK(geph4_start(config) == 0);
+    sleep(1);
+    CHECK(call_stats(stats_port, "kill") == 0);
+    geph4_stop();
+    CHECK(geph4_start(config) == 0);
+    CHECK(geph4_stop() == 0);
+
+    CHECK(__atomic_load_n(&log_lines, __ATOMIC_SEQ_CST) > 0);
+    geph4_set_log_callback(NULL, NULL);
+
+    if (failures) {
+        fprintf(stderr, "%d checks failed\n", failures);
+        return 1;
+    }
+    printf("all checks passed\n");
+    return 0;
+}
@@ -0,0 +1,10 @@
+#!/usr/bin/env bash
+# Builds the static library, links the C harness against it and runs it. Linux only.
+# The harness picks free ports itself, and CI runs this script on every push.
+set -euo pipefail
+cd "$(dirname "$0")/.."
+
+cargo build --lib ${CARGO_FLAGS:-}
+cc -Wall -Wextra -o target/ffi-harness ffi-test/harness.c target/debug/libgeph4client.a \
+    -lpthread -ldl -lm
+target/ffi-harness
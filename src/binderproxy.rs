@@ -8,14 +8,23 @@ use std::sync::Arc;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct BinderProxyOpt {
     #[structopt(flatten)]
     pub common: CommonOpt,
+
+    #[structopt(long)]
+    /// Forward requests to the geph5 broker given by the broker options, rather than the legacy binder.
+    pub broker: bool,
 }
 
 pub async fn main_binderproxy(opt: BinderProxyOpt) -> anyhow::Result<()> {
     log::info!("binder proxy mode started; send a JSON-RPC line on stdin to get a response");
-    let binder_client = Arc::new(opt.common.get_binder_client());
+    let binder_client = if opt.broker {
+        Arc::new(BinderClient(opt.common.broker_transport()))
+    } else {
+        Arc::new(opt.common.get_binder_client())
+    };
     let mut input = smol::io::BufReader::new(smol::Unblock::new(std::io::stdin()));
     let mut line = String::new();
     loop {
@@ -0,0 +1,111 @@
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use crate::config::{ConfigProblem, ConnectOpt};
+
+/// The machine-readable output of the check-config subcommand.
+#[derive(Serialize)]
+struct CheckConfigOutput {
+    valid: bool,
+    problems: Vec<ConfigProblem>,
+}
+
+/// Entry point to the check-config subcommand, which checks a full set of connect options without starting anything, printing the problems found as JSON.
+pub fn main_check_config(opt: ConnectOpt) -> anyhow::Result<()> {
+    report(opt.problems())
+}
+
+/// The error returned when check-config found problems, after printing them. The command line exits with a failure status on it.
+#[derive(Debug)]
+pub struct ConfigInvalid;
+
+impl Display for ConfigInvalid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the configuration has problems")
+    }
+}
+
+impl std::error::Error for ConfigInvalid {}
+
+/// Whether or not the check-config subcommand was requested. This is used to report errors that occur before the options are fully parsed.
+pub fn requested() -> bool {
+    subcommand(std::env::args().skip(1)).as_deref() == Some("check-config")
+}
+
+/// The subcommand named by the given arguments, which is the first one that is neither a flag nor the value of `--config`.
+fn subcommand(mut args: impl Iterator<Item = String>) -> Option<String> {
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            args.next();
+        } else if !arg.starts_with('-') {
+            return Some(arg);
+        }
+    }
+    None
+}
+
+/// Reports an error that prevented the options from being parsed at all, such as a malformed key or an unparsable config file.
+pub fn report_load_error(err: &anyhow::Error) -> anyhow::Result<()> {
+    let problem = match err.downcast_ref::<structopt::clap::Error>() {
+        Some(err) => {
+            // clap does not tell us which argument was invalid, except in the message itself
+            let message =
+                String::from_utf8_lossy(&strip_ansi_escapes::strip(&err.message)?).to_string();
+            let field = regex::Regex::new(r"'--([a-z0-9-]+)")?
+                .captures(&message)
+                .map(|caps| caps[1].replace('-', "_"));
+            ConfigProblem {
+                field,
+                message: message.trim_start_matches("error: ").to_string(),
+            }
+        }
+        None => ConfigProblem {
+            field: None,
+            message: format!("{:#}", err),
+        },
+    };
+    report(vec![problem])
+}
+
+fn report(problems: Vec<ConfigProblem>) -> anyhow::Result<()> {
+    let output = CheckConfigOutput {
+        valid: problems.is_empty(),
+        problems,
+    };
+    println!("{}", serde_json::to_string(&output)?);
+    if !output.valid {
+        return Err(ConfigInvalid.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subcommand_of(args: &[&str]) -> Option<String> {
+        subcommand(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn subcommand_is_first_positional_argument() {
+        assert_eq!(
+            subcommand_of(&["check-config"]).as_deref(),
+            Some("check-config")
+        );
+        assert_eq!(
+            subcommand_of(&["--config", "check-config", "connect"]).as_deref(),
+            Some("connect")
+        );
+        assert_eq!(
+            subcommand_of(&["--config=geph.yaml", "check-config"]).as_deref(),
+            Some("check-config")
+        );
+        assert_eq!(
+            subcommand_of(&["connect", "--exit-server", "check-config"]).as_deref(),
+            Some("connect")
+        );
+        assert_eq!(subcommand_of(&["--help"]), None);
+    }
+}
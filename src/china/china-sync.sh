@@ -1,4 +1,11 @@
 #!/bin/sh
+set -e
 
 racket generate-china.sh > china-domains.txt
-curl https://raw.githubusercontent.com/17mon/china_ip_list/master/china_ip_list.txt > china-ips.txt
\ No newline at end of file
+# download into temporary files first, so that a failed download never leaves a truncated list behind
+curl -fsS https://raw.githubusercontent.com/17mon/china_ip_list/master/china_ip_list.txt > china-ips.txt.new
+curl -fsS https://ftp.apnic.net/stats/apnic/delegated-apnic-latest | awk -F'|' '$2 == "CN" && $3 == "ipv6" { print $4 "/" $5 }' > china-ips6.txt.new
+# APNIC has thousands of Chinese IPv6 allocations, so a short list means something went wrong
+test "$(wc -l < china-ips6.txt.new)" -gt 1000
+mv china-ips.txt.new china-ips.txt
+mv china-ips6.txt.new china-ips6.txt
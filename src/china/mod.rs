@@ -1,11 +1,13 @@
 use std::collections::HashSet;
 
 use anyhow::Context;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use http_types::{Method, Request, Url};
 use once_cell::sync::Lazy;
 use treebitmap::IpLookupTable;
 
+use crate::upstream_proxy::UpstreamProxy;
+
 /// List of all Chinese domains.
 static DOMAINS: Lazy<HashSet<String>> = Lazy::new(|| {
     let ss = include_str!("china-domains.txt");
@@ -27,11 +29,31 @@ static IPLOOKUP: Lazy<IpLookupTable<Ipv4Addr, ()>> = Lazy::new(|| {
     toret
 });
 
+static IPLOOKUP6: Lazy<IpLookupTable<Ipv6Addr, ()>> = Lazy::new(|| {
+    let ss = include_str!("china-ips6.txt");
+    let mut toret = IpLookupTable::new();
+    for line in ss.split_ascii_whitespace() {
+        let vv: Vec<_> = line.split('/').collect();
+        let ip: Ipv6Addr = vv[0].parse().unwrap();
+        let plen: u32 = vv[1].parse().unwrap();
+        toret.insert(ip, plen, ());
+    }
+    toret
+});
+
 /// Returns true if the given IP is Chinese
 pub fn is_chinese_ip(ip: Ipv4Addr) -> bool {
     IPLOOKUP.longest_match(ip).is_some()
 }
 
+/// Returns true if the given IPv6 address is Chinese
+pub fn is_chinese_ipv6(ip: Ipv6Addr) -> bool {
+    match ip.to_ipv4_mapped() {
+        Some(v4) => is_chinese_ip(v4),
+        None => IPLOOKUP6.longest_match(ip).is_some(),
+    }
+}
+
 /// Returns true if the given host is Chinese
 pub fn is_chinese_host(host: &str) -> bool {
     // explode by dots
@@ -46,23 +68,30 @@ pub fn is_chinese_host(host: &str) -> bool {
     false
 }
 
-/// Returns whether or not we're in China.
+/// Returns whether or not we're in China, asking through the given proxy if there is one.
 #[cached::proc_macro::cached(result = true)]
-pub async fn test_china() -> http_types::Result<bool> {
-    let req = Request::new(
-        Method::Get,
-        Url::parse("http://checkip.amazonaws.com").unwrap(),
-    );
-    let connect_to = geph4_aioutils::resolve("checkip.amazonaws.com:80").await?;
-
-    let response = {
-        let connection =
-            smol::net::TcpStream::connect(connect_to.get(0).context("no addrs for checkip")?)
-                .await?;
-        async_h1::connect(connection, req)
-            .await?
-            .body_string()
-            .await?
+pub async fn test_china(proxy: Option<UpstreamProxy>) -> http_types::Result<bool> {
+    let response = match &proxy {
+        Some(proxy) => String::from_utf8_lossy(
+            &proxy
+                .request(Method::Get, "http://checkip.amazonaws.com", None, vec![])
+                .await?,
+        )
+        .into_owned(),
+        None => {
+            let req = Request::new(
+                Method::Get,
+                Url::parse("http://checkip.amazonaws.com").unwrap(),
+            );
+            let connect_to = geph4_aioutils::resolve("checkip.amazonaws.com:80").await?;
+            let connection =
+                smol::net::TcpStream::connect(connect_to.first().context("no addrs for checkip")?)
+                    .await?;
+            async_h1::connect(connection, req)
+                .await?
+                .body_string()
+                .await?
+        }
     };
     let response = response.trim();
     let parsed: IpAddr = response.parse()?;
@@ -71,3 +100,18 @@ pub async fn test_china() -> http_types::Result<bool> {
         IpAddr::V6(_) => Err(anyhow::anyhow!("cannot tell for ipv6").into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_lists_load() {
+        assert!(is_chinese_ip("114.114.114.114".parse().unwrap()));
+        assert!(!is_chinese_ip("8.8.8.8".parse().unwrap()));
+        assert!(is_chinese_ipv6("240e::1".parse().unwrap()));
+        assert!(is_chinese_ipv6("2402:f000::1".parse().unwrap()));
+        assert!(is_chinese_ipv6("::ffff:114.114.114.114".parse().unwrap()));
+        assert!(!is_chinese_ipv6("2001:4860:4860::8888".parse().unwrap()));
+    }
+}
@@ -0,0 +1,155 @@
+use std::{fmt::Display, net::IpAddr, str::FromStr};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// A range of IP addresses, like `192.168.1.0/24` or `fd00::/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    net: IpAddr,
+    len: u8,
+}
+
+impl Cidr {
+    /// Whether the range contains `ip`. IPv4 ranges never contain IPv6 addresses, and the other way around.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (ip, self.net) {
+            (IpAddr::V4(ip), IpAddr::V4(net)) => {
+                let mask = u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0);
+                u32::from(ip) & mask == u32::from(net) & mask
+            }
+            (IpAddr::V6(ip), IpAddr::V6(net)) => {
+                let mask = u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0);
+                u128::from(ip) & mask == u128::from(net) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (net, len) = s
+            .split_once('/')
+            .context("CIDR range has no prefix length")?;
+        let net = IpAddr::from_str(net).context("invalid CIDR address")?;
+        let len: u8 = len.parse().context("invalid CIDR prefix length")?;
+        let max = if net.is_ipv4() { 32 } else { 128 };
+        anyhow::ensure!(len <= max, "CIDR prefix length {} is too long", len);
+        Ok(Self { net, len })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(value: Cidr) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.net, self.len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_ranges() {
+        let lan = cidr("192.168.1.0/24");
+        assert!(lan.contains(ip("192.168.1.0")));
+        assert!(lan.contains(ip("192.168.1.255")));
+        assert!(!lan.contains(ip("192.168.0.255")));
+        assert!(!lan.contains(ip("192.168.2.0")));
+        // bits past the prefix length do not matter
+        assert!(cidr("192.168.1.77/24").contains(ip("192.168.1.200")));
+
+        let host = cidr("10.0.0.1/32");
+        assert!(host.contains(ip("10.0.0.1")));
+        assert!(!host.contains(ip("10.0.0.2")));
+
+        let all = cidr("0.0.0.0/0");
+        assert!(all.contains(ip("255.255.255.255")));
+        assert!(all.contains(ip("0.0.0.0")));
+    }
+
+    #[test]
+    fn ipv6_ranges() {
+        let ula = cidr("fd00::/8");
+        assert!(ula.contains(ip("fd12:3456::1")));
+        assert!(!ula.contains(ip("fc00::1")));
+        assert!(!ula.contains(ip("fe80::1")));
+
+        let subnet = cidr("2001:db8:1::/48");
+        assert!(subnet.contains(ip("2001:db8:1:ffff::1")));
+        assert!(!subnet.contains(ip("2001:db8:2::1")));
+
+        let host = cidr("::1/128");
+        assert!(host.contains(ip("::1")));
+        assert!(!host.contains(ip("::2")));
+
+        assert!(cidr("::/0").contains(ip("ffff::ffff")));
+    }
+
+    #[test]
+    fn families_never_mix() {
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(!cidr("::/0").contains(ip("127.0.0.1")));
+        // IPv4-mapped addresses are IPv6 here; callers canonicalize them first
+        assert!(!cidr("127.0.0.0/8").contains(ip("::ffff:127.0.0.1")));
+    }
+
+    #[test]
+    fn rejects_bad_ranges() {
+        for bad in [
+            "192.168.1.0",
+            "192.168.1.0/33",
+            "::/129",
+            "192.168.1/24",
+            "192.168.1.0/",
+            "192.168.1.0/-1",
+            "192.168.1.0/24/8",
+            "example.com/24",
+            "",
+        ] {
+            assert!(bad.parse::<Cidr>().is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        for s in [
+            "192.168.1.0/24",
+            "10.0.0.1/32",
+            "0.0.0.0/0",
+            "fd00::/8",
+            "::1/128",
+        ] {
+            assert_eq!(cidr(s).to_string(), s);
+            let json = serde_json::to_string(&cidr(s)).unwrap();
+            assert_eq!(serde_json::from_str::<Cidr>(&json).unwrap(), cidr(s));
+        }
+        assert!(serde_json::from_str::<Cidr>("\"10.0.0.0/40\"").is_err());
+    }
+}
@@ -1,72 +1,634 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    fmt::Display,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
 
+#[cfg(unix)]
+use async_signal::{Signal, Signals};
 use clone_macro::clone;
-use futures_util::{future::select_all, FutureExt, TryFutureExt};
 
-use itertools::Itertools;
 use once_cell::sync::Lazy;
 
+use itertools::Itertools;
+use parking_lot::RwLock;
 use rand::Rng;
+use serde_json::Value;
+use sillad::Pipe;
+use smol::channel::{Receiver, Sender};
 use smol::prelude::*;
-use smolscale::immortal::{Immortal, RespawnStrategy};
+use smol::Task;
+use smol_timeout::TimeoutExt;
 
-use crate::{config::ConnectOpt, connect::tunnel::ClientTunnel};
+use crate::{
+    config::ConnectOpt,
+    connect::{
+        acl::ListenerKind,
+        admission::{Admission, Admitted},
+        connections::Connections,
+        http_proxy::HttpUpstreams,
+        proxy_users::ProxyUsers,
+        relays::Relays,
+        routing::Router,
+        shaping::{Direction, Shaper},
+        supervisor::{HealthRegistry, Supervisor},
+        tunnel::ClientTunnel,
+    },
+    socks2http::ClientVerdict,
+};
 
 use crate::debugpack::DebugPack;
+mod acl;
+mod admission;
+mod connections;
 mod dns;
+mod hooks;
+mod http_proxy;
+mod mixed;
 
 mod port_forwarder;
+mod proxy_users;
+mod relays;
+mod routing;
+mod shaping;
+mod socks4;
 mod socks5;
 
 mod stats;
+mod supervisor;
 mod tunnel;
+mod udp_nat;
 mod vpn;
 
+pub use admission::AdmissionStats;
+pub use connections::{ConnectionInfo, Route};
+pub use proxy_users::UserStats;
+pub use routing::{Action, RouteDecision};
+pub use shaping::BandwidthLimits;
+pub use stats::BasicStats;
+pub use supervisor::SubsystemHealth;
+pub use tunnel::ConnectionStatus;
+
+/// A running connect daemon: a geph5 tunnel, plus the local listeners and VPN that use it.
 pub struct ConnectDaemon {
     ctx: ConnectContext,
-    _task: Immortal,
+    listeners_enabled: bool,
+    listeners: smol::lock::Mutex<HashMap<Listener, Supervisor>>,
+    drain_timeout: Duration,
+    recv_reload: Receiver<Sender<bool>>,
+    recv_shutdown: Receiver<()>,
+    vpn_task: Supervisor,
+    _log_writer: Task<()>,
 }
 
-impl ConnectDaemon {
-    /// Starts a new ConnectClient. If initialization fails, returns an error.
-    pub async fn start(opt: ConnectOpt) -> anyhow::Result<Self> {
+/// Configures a [`ConnectDaemon`] before starting it.
+pub struct ConnectDaemonBuilder {
+    opt: ConnectOpt,
+    listeners_enabled: bool,
+    drain_timeout: Duration,
+}
+
+impl ConnectDaemonBuilder {
+    /// Creates a builder with the given options and the defaults for everything else.
+    pub fn new(opt: ConnectOpt) -> Self {
+        Self {
+            opt,
+            listeners_enabled: true,
+            drain_timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Whether to open the local listeners: SOCKS5, HTTP, DNS, stats and port forwards. Embedders that only use [`ConnectDaemon::connect_stream`] can turn them off. Defaults to on.
+    pub fn listeners(mut self, enabled: bool) -> Self {
+        self.listeners_enabled = enabled;
+        self
+    }
+
+    /// How long [`ConnectDaemon::shutdown`] waits for in-flight relays to finish before cutting them off. Defaults to 5 seconds.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Starts the daemon. If initialization fails, returns an error.
+    pub async fn start(self) -> anyhow::Result<ConnectDaemon> {
+        let opt = self.opt;
+        let logs = crate::logs::subscribe_logs();
         log::info!(
-            "connect mode starting: exit = {:?}, force_protocol = {:?}, use_bridges = {}",
+            "connect mode starting: exit = {:?}, country = {:?}, city = {:?}, force_protocol = {:?}, use_bridges = {}",
             opt.exit_server,
+            opt.exit_country,
+            opt.exit_city,
             opt.force_protocol,
             opt.use_bridges
         );
 
-        let tunnel = ClientTunnel::new(opt.clone()).into();
+        // legacy options that geph5 cannot honor are rejected here, rather than silently ignored
+        opt.validate()?;
+        let tunnel = ClientTunnel::new(opt.clone())
+            .await
+            .context("cannot start tunnel")?
+            .into();
+        let users = Arc::new(ProxyUsers::default());
+        users.set(ProxyUsers::read(opt.proxy_credentials.as_deref())?);
+        let router = Arc::new(Router::default());
+        router.set(Router::read(opt.routing_rules.as_deref())?);
+        let (send_reload, recv_reload) = smol::channel::bounded(1);
+        let (send_shutdown, recv_shutdown) = smol::channel::bounded(1);
         let ctx = ConnectContext {
             tunnel,
-            opt: opt.clone(),
             debug: Arc::new(DebugPack::new(&opt.common.debugpack_path)?),
+            opt: Arc::new(RwLock::new(Arc::new(opt.clone()))),
+            relays: Default::default(),
+            connections: Default::default(),
+            admission: Default::default(),
+            subsystems: Default::default(),
+            users,
+            router,
+            shaper: Default::default(),
+            send_reload,
+            send_shutdown,
         };
-        Ok(Self {
+        let listeners = Listener::all(&opt, self.listeners_enabled)
+            .into_iter()
+            .map(|listener| {
+                let task = listener.spawn(&ctx);
+                (listener, task)
+            })
+            .collect();
+        Ok(ConnectDaemon {
             ctx: ctx.clone(),
-            _task: Immortal::respawn(
-                RespawnStrategy::JitterDelay(Duration::from_secs(1), Duration::from_secs(5)),
-                clone!([ctx], move || connect_loop(ctx.clone())
-                    .map_err(|e| log::error!("connect_loop restart: {:?}", e))),
+            listeners_enabled: self.listeners_enabled,
+            listeners: smol::lock::Mutex::new(listeners),
+            drain_timeout: self.drain_timeout,
+            recv_reload,
+            recv_shutdown,
+            vpn_task: Supervisor::spawn(
+                "vpn".into(),
+                ctx.subsystems.clone(),
+                clone!([ctx], move || vpn::vpn_loop(ctx.clone())),
             ),
+            _log_writer: smolscale::spawn(async move {
+                while let Ok(line) = logs.recv().await {
+                    ctx.debug.add_logline(&line);
+                }
+            }),
         })
     }
+}
+
+impl ConnectDaemon {
+    /// Starts a new ConnectClient with the default settings. If initialization fails, returns an error.
+    pub async fn start(opt: ConnectOpt) -> anyhow::Result<Self> {
+        Self::builder(opt).start().await
+    }
+
+    /// Creates a builder for a daemon with the given options.
+    pub fn builder(opt: ConnectOpt) -> ConnectDaemonBuilder {
+        ConnectDaemonBuilder::new(opt)
+    }
+
+    /// The state shared by everything in the daemon, which can be queried without holding on to the daemon itself.
+    pub(crate) fn context(&self) -> ConnectContext {
+        self.ctx.clone()
+    }
+
+    /// The options currently in effect.
+    pub fn opt(&self) -> Arc<ConnectOpt> {
+        self.ctx.opt()
+    }
+
+    /// Returns the current connection status of the tunnel. If it cannot be found out, the tunnel is reported as connecting.
+    pub async fn status(&self) -> ConnectionStatus {
+        self.ctx.status().await
+    }
+
+    /// Returns the latest tunnel statistics, or None if the tunnel has not connected yet.
+    pub fn stats(&self) -> Option<BasicStats> {
+        self.ctx.stats()
+    }
+
+    /// Returns the health of every subsystem: each listener, and the VPN.
+    pub fn subsystem_health(&self) -> BTreeMap<String, SubsystemHealth> {
+        self.ctx.subsystem_health()
+    }
+
+    /// Returns how much each proxy user has sent and received, when `--proxy-credentials` is in use.
+    pub fn user_stats(&self) -> BTreeMap<String, UserStats> {
+        self.ctx.users.stats()
+    }
+
+    /// Returns every open proxied connection from the local proxies and port forwards.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.ctx.connections.list()
+    }
+
+    /// Closes the proxied connection with the given ID, returning whether there was one.
+    pub fn close_connection(&self, id: u64) -> bool {
+        self.ctx.connections.close(id)
+    }
+
+    /// Returns how busy the local listeners are, and how many connections and DNS queries they have turned away.
+    pub fn admission_stats(&self) -> AdmissionStats {
+        self.ctx.admission.stats()
+    }
+
+    /// Says where a proxied connection to `host` would go under the current routing rules, and which rule decides it.
+    pub fn route(&self, host: &str) -> RouteDecision {
+        self.ctx.route(host)
+    }
+
+    /// Returns the bandwidth limits currently in effect.
+    pub fn bandwidth_limits(&self) -> BandwidthLimits {
+        self.ctx.bandwidth_limits()
+    }
+
+    /// Changes the bandwidth limits until the configuration is next reloaded. Open connections slow down or speed up right away.
+    pub fn set_bandwidth_limits(&self, limits: BandwidthLimits) -> anyhow::Result<()> {
+        self.ctx.set_bandwidth_limits(limits)
+    }
+
+    /// Opens a stream to the given `host:port` through the tunnel.
+    pub async fn connect_stream(&self, remote: &str) -> anyhow::Result<Box<dyn Pipe>> {
+        self.ctx.tunnel.connect_stream(remote).await
+    }
+
+    /// Waits until a configuration reload is requested, either through SIGHUP or through the stats RPC. The caller reloads and then reports how it went to [`ReloadRequest::finish`].
+    pub async fn reload_requested(&self) -> ReloadRequest {
+        let rpc = async {
+            match self.recv_reload.recv().await {
+                Ok(reply) => ReloadRequest { reply: Some(reply) },
+                Err(_) => smol::future::pending().await,
+            }
+        };
+        #[cfg(unix)]
+        let rpc = rpc.race(async {
+            match Signals::new([Signal::Hup]) {
+                Ok(mut signals) => {
+                    signals.next().await;
+                    ReloadRequest { reply: None }
+                }
+                Err(err) => {
+                    log::warn!("cannot listen for SIGHUP: {:?}", err);
+                    smol::future::pending().await
+                }
+            }
+        });
+        rpc.await
+    }
+
+    /// Waits until a shutdown is requested, either through a termination signal or through the stats RPC. The daemon does not shut itself down: whoever drives it must wait for this (or for [`ConnectDaemon::kill_requested`]) and then call [`ConnectDaemon::shutdown`].
+    pub async fn shutdown_requested(&self) {
+        let rpc = self.kill_requested();
+        #[cfg(unix)]
+        let rpc = rpc.race(async {
+            match Signals::new([Signal::Term, Signal::Quit, Signal::Int]) {
+                Ok(mut signals) => {
+                    signals.next().await;
+                }
+                Err(err) => {
+                    log::warn!("cannot listen for termination signals: {:?}", err);
+                    smol::future::pending().await
+                }
+            }
+        });
+        rpc.await
+    }
+
+    /// Waits until a shutdown is requested through the `kill` method of the stats RPC. Unlike [`ConnectDaemon::shutdown_requested`], this leaves signals alone, for embedders whose host process handles them itself, and it does not borrow the daemon, so it can be waited for while the daemon is elsewhere.
+    pub fn kill_requested(&self) -> impl Future<Output = ()> + Send + 'static {
+        let recv_shutdown = self.recv_shutdown.clone();
+        async move {
+            if recv_shutdown.recv().await.is_err() {
+                smol::future::pending().await
+            }
+        }
+    }
+
+    /// Shuts down the daemon. New connections are refused right away, in-flight relays get until the drain timeout to finish, and then VPN routing is torn down and the debug pack is flushed to disk.
+    pub async fn shutdown(self) {
+        log::info!("shutting down");
+        let deadline = Instant::now() + self.drain_timeout;
+        let listeners = std::mem::take(&mut *self.listeners.lock().await);
+        for (_, task) in listeners {
+            task.cancel().await;
+        }
+        let cut_off = self.ctx.relays.close(deadline).await;
+        if cut_off > 0 {
+            log::warn!("cut off {} relays that did not finish in time", cut_off);
+        }
+        // dropping the VPN loop is what tears down the routing
+        self.vpn_task.cancel().await;
+        let debug = self.ctx.debug.clone();
+        smol::unblock(move || debug.flush_and_close()).await;
+    }
 
-    /// Gets a handle to the debug pack from the outside.
-    pub fn debug(&self) -> &DebugPack {
-        &self.ctx.debug
+    /// Applies new options without dropping the tunnel. Listeners whose addresses changed are rebound, and policy like `exclude_prc` and `tunnel_down_grace` applies to new connections. If any option outside [RELOADABLE] changed, nothing is applied and the error names those options, since they need a new geph5 session. Nothing is applied either if the credentials or rules file cannot be read.
+    pub async fn reconfigure(&self, new_opt: ConnectOpt) -> anyhow::Result<()> {
+        new_opt.validate()?;
+        let not_reloadable = changed_fields(&self.ctx.opt(), &new_opt)?
+            .into_iter()
+            .filter(|field| !RELOADABLE.contains(&field.as_str()))
+            .collect_vec();
+        if !not_reloadable.is_empty() {
+            anyhow::bail!(
+                "changing {} requires a restart; nothing was reloaded",
+                not_reloadable.join(", ")
+            )
+        }
+        // the credentials and rules files are re-read even if their paths stay the same, and both are read before either is swapped in, so that a bad one leaves the old configuration whole
+        let logins = ProxyUsers::read(new_opt.proxy_credentials.as_deref())?;
+        let rules = Router::read(new_opt.routing_rules.as_deref())?;
+        let wanted = Listener::all(&new_opt, self.listeners_enabled);
+        self.ctx.users.set(logins);
+        self.ctx.router.set(rules);
+        *self.ctx.opt.write() = Arc::new(new_opt);
+        let mut listeners = self.listeners.lock().await;
+        listeners.retain(|listener, _| {
+            let keep = wanted.contains(listener);
+            if !keep {
+                log::info!("closing {}", listener);
+            }
+            keep
+        });
+        for listener in wanted {
+            if let Entry::Vacant(entry) = listeners.entry(listener) {
+                log::info!("opening {}", entry.key());
+                let task = entry.key().spawn(&self.ctx);
+                entry.insert(task);
+            }
+        }
+        Ok(())
     }
 }
 
+/// A configuration reload asked for through [`ConnectDaemon::reload_requested`]. If it came from the stats RPC, the caller is waiting to hear whether it went through.
+pub struct ReloadRequest {
+    reply: Option<Sender<bool>>,
+}
+
+impl ReloadRequest {
+    /// Reports how the reload went to whoever asked for it.
+    pub fn finish(self, result: &anyhow::Result<()>) {
+        if let Some(reply) = self.reply {
+            let _ = reply.try_send(result.is_ok());
+        }
+    }
+}
+
+/// How long the `reload_config` RPC waits for whoever drives the daemon to reload, before giving up.
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The options that [ConnectDaemon::reconfigure] can change without a restart, by their names in config files.
+const RELOADABLE: &[&str] = &[
+    "http_listen",
+    "socks5_listen",
+    "mixed_listen",
+    "stats_listen",
+    "dns_listen",
+    "forward_ports",
+    "exclude_prc",
+    "tunnel_down_grace",
+    "max_connections",
+    "max_connections_per_client",
+    "connection_rate_per_client",
+    "max_dns_in_flight",
+    "socks5_allow",
+    "socks5_deny",
+    "http_allow",
+    "http_deny",
+    "mixed_allow",
+    "mixed_deny",
+    "dns_allow",
+    "dns_deny",
+    "stats_allow",
+    "stats_deny",
+    "forward_allow",
+    "forward_deny",
+    "upload_limit",
+    "download_limit",
+    "per_connection_limit",
+    "proxy_credentials",
+    "routing_rules",
+    "direct_upstream",
+];
+
+/// Names the top-level fields that differ between two sets of options.
+fn changed_fields(a: &ConnectOpt, b: &ConnectOpt) -> anyhow::Result<Vec<String>> {
+    let (Value::Object(a), Value::Object(b)) = (serde_json::to_value(a)?, serde_json::to_value(b)?)
+    else {
+        anyhow::bail!("options did not serialize to an object")
+    };
+    Ok(a.into_iter()
+        .filter(|(k, v)| b.get(k) != Some(v))
+        .map(|(k, _)| k)
+        .collect())
+}
+
 #[derive(Clone)]
 pub struct ConnectContext {
-    opt: ConnectOpt,
+    opt: Arc<RwLock<Arc<ConnectOpt>>>,
 
     tunnel: Arc<ClientTunnel>,
     debug: Arc<DebugPack>,
+    relays: Arc<Relays>,
+    connections: Arc<Connections>,
+    admission: Arc<Admission>,
+    subsystems: HealthRegistry,
+    users: Arc<ProxyUsers>,
+    router: Arc<Router>,
+    shaper: Arc<Shaper>,
+    send_reload: Sender<Sender<bool>>,
+    send_shutdown: Sender<()>,
+}
+
+impl ConnectContext {
+    /// The options currently in effect. These may change at runtime when the configuration is reloaded, or when the bandwidth limits are changed over the stats RPC.
+    fn opt(&self) -> Arc<ConnectOpt> {
+        self.opt.read().clone()
+    }
+
+    /// The current connection status of the tunnel.
+    pub(crate) async fn status(&self) -> ConnectionStatus {
+        self.tunnel.status().await
+    }
+
+    /// The latest tunnel statistics, if the tunnel has connected yet.
+    pub(crate) fn stats(&self) -> Option<BasicStats> {
+        stats::latest_basic_stats()
+    }
+
+    /// The health of every subsystem, by name.
+    pub(crate) fn subsystem_health(&self) -> BTreeMap<String, SubsystemHealth> {
+        self.subsystems
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    /// Whether `client` may use a listener of the given kind under its access control list, logging it if not.
+    fn permit(&self, kind: ListenerKind, client: SocketAddr) -> bool {
+        let permitted = acl::permits(&self.opt(), kind, client.ip());
+        if !permitted {
+            log::info!(
+                "{} listener refusing {}: not allowed by its access control list",
+                kind,
+                client
+            );
+        }
+        permitted
+    }
+
+    /// Admits a connection that a listener just accepted, or logs why not. The connection should be dropped right away if it is refused.
+    fn admit(&self, client: SocketAddr) -> Option<Admitted> {
+        match self.admission.admit(&self.opt(), client.ip()) {
+            Ok(admitted) => Some(admitted),
+            Err(refusal) => {
+                log::debug!("refusing connection from {}: {:?}", client, refusal);
+                None
+            }
+        }
+    }
+
+    /// Says where a proxied connection to `host` would go, with `exclude_prc` as currently configured.
+    fn route(&self, host: &str) -> RouteDecision {
+        let ip = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .ok();
+        self.router.route(host, ip, self.opt().exclude_prc)
+    }
+
+    /// The bandwidth limits currently in effect.
+    fn bandwidth_limits(&self) -> BandwidthLimits {
+        BandwidthLimits::of(&self.opt())
+    }
+
+    /// Changes the bandwidth limits in the options in effect, leaving everything else alone.
+    fn set_bandwidth_limits(&self, limits: BandwidthLimits) -> anyhow::Result<()> {
+        for limit in [limits.upload, limits.download, limits.per_connection] {
+            anyhow::ensure!(
+                limit != Some(0),
+                "bandwidth limits must be at least 1; leave them out for no limit"
+            );
+        }
+        let mut opt = self.opt.write();
+        let mut new_opt = (**opt).clone();
+        limits.apply(&mut new_opt);
+        *opt = Arc::new(new_opt);
+        Ok(())
+    }
+
+    /// Waits until `n` bytes may go through the tunnel in `direction` under the global bandwidth limits.
+    async fn shape(&self, direction: Direction, n: usize) {
+        self.shaper
+            .pass(&self.bandwidth_limits(), direction, n)
+            .await
+    }
+
+    /// Asks whoever drives the daemon to reload the configuration, and waits for whether the reload went through.
+    async fn request_reload(&self) -> anyhow::Result<()> {
+        let (send_done, recv_done) = smol::channel::bounded(1);
+        self.send_reload
+            .send(send_done)
+            .timeout(RELOAD_TIMEOUT)
+            .await
+            .context("another reload is still pending")??;
+        match recv_done.recv().timeout(RELOAD_TIMEOUT).await {
+            Some(Ok(true)) => Ok(()),
+            Some(Ok(false)) => anyhow::bail!("the configuration could not be reloaded"),
+            Some(Err(_)) => anyhow::bail!("the reload was abandoned"),
+            None => anyhow::bail!(
+                "nothing reloaded the configuration within {:?}",
+                RELOAD_TIMEOUT
+            ),
+        }
+    }
+
+    /// Asks whoever drives the daemon to shut it down.
+    fn request_shutdown(&self) {
+        let _ = self.send_shutdown.try_send(());
+    }
+}
+
+/// A local listener. Two listeners compare equal exactly when neither needs to be rebound to turn into the other.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Listener {
+    Http(SocketAddr),
+    Socks5(SocketAddr),
+    Mixed(SocketAddr),
+    Dns(SocketAddr),
+    Stats(SocketAddr),
+    Forward(String),
+}
+
+impl Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listener::Http(addr) => write!(f, "http {}", addr),
+            Listener::Socks5(addr) => write!(f, "socks5 {}", addr),
+            Listener::Mixed(addr) => write!(f, "mixed {}", addr),
+            Listener::Dns(addr) => write!(f, "dns {}", addr),
+            Listener::Stats(addr) => write!(f, "stats {}", addr),
+            Listener::Forward(desc) => write!(f, "forward {}", desc),
+        }
+    }
+}
+
+impl Listener {
+    /// All the listeners that the options ask for, if listeners are enabled at all.
+    fn all(opt: &ConnectOpt, enabled: bool) -> Vec<Self> {
+        if !enabled {
+            return vec![];
+        }
+        [
+            Listener::Http(opt.http_listen),
+            Listener::Socks5(opt.socks5_listen),
+            Listener::Dns(opt.dns_listen),
+            Listener::Stats(opt.stats_listen),
+        ]
+        .into_iter()
+        .chain(opt.mixed_listen.map(Listener::Mixed))
+        .chain(opt.forward_ports.iter().cloned().map(Listener::Forward))
+        .collect()
+    }
+
+    /// Runs the listener in the background, restarting it whenever it fails.
+    fn spawn(&self, ctx: &ConnectContext) -> Supervisor {
+        let this = self.clone();
+        let ctx = ctx.clone();
+        Supervisor::spawn(self.to_string(), ctx.subsystems.clone(), move || {
+            this.clone().run(ctx.clone())
+        })
+    }
+
+    async fn run(self, ctx: ConnectContext) -> anyhow::Result<()> {
+        match self {
+            Listener::Http(addr) => {
+                let upstreams = Arc::new(HttpUpstreams(ctx.clone()));
+                crate::socks2http::run_tokio(addr, upstreams, move |client| {
+                    if !ctx.permit(ListenerKind::Http, client) {
+                        return ClientVerdict::Forbid;
+                    }
+                    match ctx.admit(client) {
+                        Some(admitted) => ClientVerdict::Serve(admitted),
+                        None => ClientVerdict::Close,
+                    }
+                })
+                .await
+            }
+            Listener::Socks5(addr) => socks5::socks5_loop(ctx, addr).await,
+            Listener::Mixed(addr) => mixed::mixed_loop(ctx, addr).await,
+            Listener::Dns(addr) => dns::dns_loop(ctx, addr).await,
+            Listener::Stats(addr) => stats::serve_stats_loop(ctx, addr).await,
+            Listener::Forward(desc) => port_forwarder::port_forwarder(ctx, desc).await,
+        }
+    }
 }
 
 static METRIC_SESSION_ID: Lazy<i64> = Lazy::new(|| {
@@ -74,35 +636,36 @@ static METRIC_SESSION_ID: Lazy<i64> = Lazy::new(|| {
     rng.gen()
 });
 
-async fn connect_loop(ctx: ConnectContext) -> anyhow::Result<()> {
-    let socks2http = smolscale::spawn(crate::socks2http::run_tokio(ctx.opt.http_listen, {
-        let mut addr = ctx.opt.socks5_listen;
-        addr.set_ip("127.0.0.1".parse().unwrap());
-        addr
-    }));
-
-    let socks5 = smolscale::spawn(socks5::socks5_loop(ctx.clone()));
-
-    let dns = smolscale::spawn(dns::dns_loop(ctx.clone(), ctx.opt.dns_listen));
-
-    let forward_ports = ctx
-        .opt
-        .forward_ports
-        .iter()
-        .map(|v| smolscale::spawn(port_forwarder::port_forwarder(ctx.clone(), v.clone())))
-        .chain(std::iter::once(smolscale::spawn(smol::future::pending()))) // ensures there's at least one
-        .collect_vec();
-
-    let vpn = smolscale::spawn(vpn::vpn_loop(ctx.clone()));
-
-    let stats = smolscale::spawn(stats::serve_stats_loop(ctx.clone()));
-
-    socks2http
-        .race(socks5)
-        .race(dns)
-        .race(select_all(forward_ports).map(|s| s.0))
-        .race(vpn)
-        .race(stats)
-        .await?;
-    anyhow::bail!("somehow ran off the edge of a cliff")
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use super::*;
+
+    #[test]
+    fn reloadable_fields_are_options() {
+        let opt = ConnectOpt::from_iter_safe(["geph4-client"]).unwrap();
+        let Value::Object(fields) = serde_json::to_value(opt).unwrap() else {
+            panic!("options did not serialize to an object")
+        };
+        for field in RELOADABLE {
+            assert!(fields.contains_key(*field), "{} is not an option", field);
+        }
+    }
+
+    #[test]
+    fn changed_fields_names_top_level_fields() {
+        let a = ConnectOpt::from_iter_safe(["geph4-client"]).unwrap();
+        let b = ConnectOpt::from_iter_safe([
+            "geph4-client",
+            "--exclude-prc",
+            "--broker-url",
+            "https://broker.example",
+        ])
+        .unwrap();
+        let mut changed = changed_fields(&a, &b).unwrap();
+        changed.sort();
+        assert_eq!(changed, ["common", "exclude_prc"]);
+        assert!(changed_fields(&a, &a).unwrap().is_empty());
+    }
 }
@@ -0,0 +1,115 @@
+use std::{fmt::Display, net::IpAddr};
+
+use crate::{cidr::Cidr, config::ConnectOpt};
+
+/// The kinds of local listener, each with its own `--<kind>-allow` and `--<kind>-deny` lists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListenerKind {
+    Socks5,
+    Http,
+    Mixed,
+    Dns,
+    Stats,
+    Forward,
+}
+
+impl ListenerKind {
+    fn lists(self, opt: &ConnectOpt) -> (&[Cidr], &[Cidr]) {
+        match self {
+            ListenerKind::Socks5 => (&opt.socks5_allow, &opt.socks5_deny),
+            ListenerKind::Http => (&opt.http_allow, &opt.http_deny),
+            ListenerKind::Mixed => (&opt.mixed_allow, &opt.mixed_deny),
+            ListenerKind::Dns => (&opt.dns_allow, &opt.dns_deny),
+            ListenerKind::Stats => (&opt.stats_allow, &opt.stats_deny),
+            ListenerKind::Forward => (&opt.forward_allow, &opt.forward_deny),
+        }
+    }
+}
+
+/// Whether `client` may use a listener of the given kind. Denying wins over allowing, and an empty allow list allows everyone.
+pub fn permits(opt: &ConnectOpt, kind: ListenerKind, client: IpAddr) -> bool {
+    // dual-stack listeners see IPv4 clients as IPv4-mapped IPv6 addresses
+    let client = client.to_canonical();
+    let (allow, deny) = kind.lists(opt);
+    if deny.iter().any(|cidr| cidr.contains(client)) {
+        return false;
+    }
+    allow.is_empty() || allow.iter().any(|cidr| cidr.contains(client))
+}
+
+impl Display for ListenerKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenerKind::Socks5 => write!(f, "socks5"),
+            ListenerKind::Http => write!(f, "http"),
+            ListenerKind::Mixed => write!(f, "mixed"),
+            ListenerKind::Dns => write!(f, "dns"),
+            ListenerKind::Stats => write!(f, "stats"),
+            ListenerKind::Forward => write!(f, "forward"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use super::*;
+
+    fn opt(args: &[&str]) -> ConnectOpt {
+        ConnectOpt::from_iter_safe(["geph4-client"].iter().chain(args)).unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn empty_allow_list_allows_everyone() {
+        let opt = opt(&["--socks5-deny", "10.0.0.0/8"]);
+        assert!(permits(&opt, ListenerKind::Socks5, ip("192.0.2.1")));
+        assert!(permits(&opt, ListenerKind::Socks5, ip("2001:db8::1")));
+        assert!(!permits(&opt, ListenerKind::Socks5, ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let opt = opt(&[
+            "--socks5-allow",
+            "192.168.1.0/24",
+            "--socks5-deny",
+            "192.168.1.13/32",
+        ]);
+        assert!(permits(&opt, ListenerKind::Socks5, ip("192.168.1.12")));
+        assert!(!permits(&opt, ListenerKind::Socks5, ip("192.168.1.13")));
+        // a non-empty allow list leaves out everyone else
+        assert!(!permits(&opt, ListenerKind::Socks5, ip("192.168.2.1")));
+        // each kind of listener has its own lists
+        assert!(permits(&opt, ListenerKind::Http, ip("192.168.1.13")));
+    }
+
+    #[test]
+    fn ipv4_mapped_clients_are_canonicalized() {
+        let opt = opt(&[
+            "--mixed-allow",
+            "192.168.1.0/24",
+            "--mixed-deny",
+            "192.168.1.13/32",
+        ]);
+        assert!(permits(
+            &opt,
+            ListenerKind::Mixed,
+            ip("::ffff:192.168.1.12")
+        ));
+        assert!(!permits(
+            &opt,
+            ListenerKind::Mixed,
+            ip("::ffff:192.168.1.13")
+        ));
+        assert!(!permits(
+            &opt,
+            ListenerKind::Mixed,
+            ip("::ffff:192.168.2.1")
+        ));
+    }
+}
@@ -0,0 +1,288 @@
+use std::{
+    net::IpAddr,
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use governor::{clock::DefaultClock, state::keyed::DefaultKeyedStateStore, Quota, RateLimiter};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConnectOpt;
+
+type ClientRateLimiter = RateLimiter<IpAddr, DefaultKeyedStateStore<IpAddr>, DefaultClock>;
+
+/// How many clients the rate limiter may remember before it forgets the ones that have gone quiet.
+const RATE_LIMITER_PRUNE_AT: usize = 1024;
+
+/// How busy the local listeners are, and how much they have turned away, as reported over the stats RPC.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AdmissionStats {
+    pub active_connections: u64,
+    pub dns_in_flight: u64,
+    /// Connections refused because `--max-connections` were already open.
+    pub rejected_over_total: u64,
+    /// Connections refused because their client already had `--max-connections-per-client` open.
+    pub rejected_over_client: u64,
+    /// Connections refused because their client opened them faster than `--connection-rate-per-client`.
+    pub rejected_rate: u64,
+    /// DNS queries dropped because `--max-dns-in-flight` were already being answered.
+    pub rejected_dns: u64,
+}
+
+/// Why a connection was turned away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refusal {
+    OverTotal,
+    OverClient,
+    Rate,
+}
+
+/// Decides whether the local listeners take on new connections and DNS queries, so that one misbehaving app cannot exhaust file descriptors or flood the tunnel. The limits come from the options in effect at the time, so reloading the configuration changes them.
+#[derive(Default)]
+pub struct Admission {
+    total: Arc<AtomicUsize>,
+    per_client: Arc<DashMap<IpAddr, usize>>,
+    dns_in_flight: Arc<AtomicUsize>,
+    /// The rate limiter, along with the per-second rate it was built for.
+    rate: Mutex<Option<(NonZeroU32, Arc<ClientRateLimiter>)>>,
+    rejected_over_total: AtomicU64,
+    rejected_over_client: AtomicU64,
+    rejected_rate: AtomicU64,
+    rejected_dns: AtomicU64,
+}
+
+impl Admission {
+    /// Admits a new connection from `client`, or says why not. The connection counts against the limits until the returned guard is dropped.
+    pub fn admit(&self, opt: &ConnectOpt, client: IpAddr) -> Result<Admitted, Refusal> {
+        let refusal = self.check(opt, client);
+        if let Err(refusal) = refusal {
+            match refusal {
+                Refusal::OverTotal => &self.rejected_over_total,
+                Refusal::OverClient => &self.rejected_over_client,
+                Refusal::Rate => &self.rejected_rate,
+            }
+            .fetch_add(1, Ordering::Relaxed);
+        }
+        refusal
+    }
+
+    fn check(&self, opt: &ConnectOpt, client: IpAddr) -> Result<Admitted, Refusal> {
+        let total = self.total.fetch_add(1, Ordering::SeqCst) + 1;
+        // from here on, dropping the guard undoes whatever was counted
+        let mut admitted = Admitted {
+            total: self.total.clone(),
+            per_client: None,
+        };
+        if opt.max_connections.is_some_and(|max| total > max) {
+            return Err(Refusal::OverTotal);
+        }
+        let mut count = self.per_client.entry(client).or_insert(0);
+        *count += 1;
+        let over_client = opt
+            .max_connections_per_client
+            .is_some_and(|max| *count > max);
+        drop(count);
+        admitted.per_client = Some((self.per_client.clone(), client));
+        if over_client {
+            return Err(Refusal::OverClient);
+        }
+        // checked last, so that connections refused for the other limits do not use up the client's rate
+        if let Some(limiter) = self.rate_limiter(opt.connection_rate_per_client) {
+            if limiter.len() > RATE_LIMITER_PRUNE_AT {
+                limiter.retain_recent();
+            }
+            limiter.check_key(&client).map_err(|_| Refusal::Rate)?;
+        }
+        Ok(admitted)
+    }
+
+    fn rate_limiter(&self, rate: Option<u32>) -> Option<Arc<ClientRateLimiter>> {
+        let rate = NonZeroU32::new(rate?)?;
+        let mut current = self.rate.lock();
+        match current.as_ref() {
+            Some((current_rate, limiter)) if *current_rate == rate => Some(limiter.clone()),
+            _ => {
+                let limiter = Arc::new(RateLimiter::keyed(Quota::per_second(rate)));
+                *current = Some((rate, limiter.clone()));
+                Some(limiter)
+            }
+        }
+    }
+
+    /// Admits a DNS query, or returns None if too many are already in flight. The query counts as in flight until the returned guard is dropped.
+    pub fn admit_dns(&self, opt: &ConnectOpt) -> Option<DnsAdmitted> {
+        let in_flight = self.dns_in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        let admitted = DnsAdmitted(self.dns_in_flight.clone());
+        if opt.max_dns_in_flight.is_some_and(|max| in_flight > max) {
+            self.rejected_dns.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(admitted)
+    }
+
+    pub fn stats(&self) -> AdmissionStats {
+        AdmissionStats {
+            active_connections: self.total.load(Ordering::SeqCst) as u64,
+            dns_in_flight: self.dns_in_flight.load(Ordering::SeqCst) as u64,
+            rejected_over_total: self.rejected_over_total.load(Ordering::Relaxed),
+            rejected_over_client: self.rejected_over_client.load(Ordering::Relaxed),
+            rejected_rate: self.rejected_rate.load(Ordering::Relaxed),
+            rejected_dns: self.rejected_dns.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// An admitted connection's place under the limits, given back when dropped.
+pub struct Admitted {
+    total: Arc<AtomicUsize>,
+    per_client: Option<(Arc<DashMap<IpAddr, usize>>, IpAddr)>,
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        self.total.fetch_sub(1, Ordering::SeqCst);
+        if let Some((per_client, client)) = self.per_client.take() {
+            if let Entry::Occupied(mut entry) = per_client.entry(client) {
+                *entry.get_mut() -= 1;
+                if *entry.get() == 0 {
+                    entry.remove();
+                }
+            }
+        }
+    }
+}
+
+/// An admitted DNS query's place under the limit, given back when dropped.
+pub struct DnsAdmitted(Arc<AtomicUsize>);
+
+impl Drop for DnsAdmitted {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use super::*;
+
+    fn opt() -> ConnectOpt {
+        ConnectOpt::from_iter_safe(["geph4-client"]).unwrap()
+    }
+
+    fn client(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    #[test]
+    fn total_limit() {
+        let admission = Admission::default();
+        let mut opt = opt();
+        opt.max_connections = Some(2);
+        let first = admission.admit(&opt, client(1)).unwrap();
+        let _second = admission.admit(&opt, client(2)).unwrap();
+        assert_eq!(
+            admission.admit(&opt, client(3)).err(),
+            Some(Refusal::OverTotal)
+        );
+        // the refused connection took no place
+        assert_eq!(admission.stats().active_connections, 2);
+        assert!(!admission.per_client.contains_key(&client(3)));
+        drop(first);
+        assert_eq!(admission.stats().active_connections, 1);
+        assert!(!admission.per_client.contains_key(&client(1)));
+        assert!(admission.admit(&opt, client(3)).is_ok());
+    }
+
+    #[test]
+    fn per_client_limit() {
+        let admission = Admission::default();
+        let mut opt = opt();
+        opt.max_connections_per_client = Some(1);
+        let first = admission.admit(&opt, client(1)).unwrap();
+        assert_eq!(
+            admission.admit(&opt, client(1)).err(),
+            Some(Refusal::OverClient)
+        );
+        let other = admission.admit(&opt, client(2)).unwrap();
+        assert_eq!(admission.stats().active_connections, 2);
+        assert_eq!(admission.per_client.get(&client(1)).map(|n| *n), Some(1));
+        drop(first);
+        drop(other);
+        assert_eq!(admission.stats().active_connections, 0);
+        assert!(admission.per_client.is_empty());
+    }
+
+    #[test]
+    fn refusals_do_not_use_up_the_rate() {
+        let admission = Admission::default();
+        let mut opt = opt();
+        opt.max_connections = Some(1);
+        opt.connection_rate_per_client = Some(1);
+        let held = admission.admit(&opt, client(1)).unwrap();
+        assert_eq!(
+            admission.admit(&opt, client(2)).err(),
+            Some(Refusal::OverTotal)
+        );
+        drop(held);
+        let admitted = admission.admit(&opt, client(2)).unwrap();
+        drop(admitted);
+        assert_eq!(admission.admit(&opt, client(2)).err(), Some(Refusal::Rate));
+        assert_eq!(admission.stats().active_connections, 0);
+        assert!(admission.per_client.is_empty());
+    }
+
+    #[test]
+    fn dns_in_flight() {
+        let admission = Admission::default();
+        let mut opt = opt();
+        opt.max_dns_in_flight = Some(1);
+        let query = admission.admit_dns(&opt).unwrap();
+        assert!(admission.admit_dns(&opt).is_none());
+        assert_eq!(admission.stats().dns_in_flight, 1);
+        drop(query);
+        assert_eq!(admission.stats().dns_in_flight, 0);
+        assert!(admission.admit_dns(&opt).is_some());
+    }
+
+    #[test]
+    fn rejections_are_counted() {
+        let admission = Admission::default();
+        let mut opt = opt();
+        opt.max_connections = Some(2);
+        opt.max_connections_per_client = Some(1);
+        opt.connection_rate_per_client = Some(1);
+        opt.max_dns_in_flight = Some(0);
+        let _first = admission.admit(&opt, client(1)).unwrap();
+        let _second = admission.admit(&opt, client(2)).unwrap();
+        // over the total, and so never checked against the client's limit
+        assert!(admission.admit(&opt, client(1)).is_err());
+        assert!(admission.admit(&opt, client(3)).is_err());
+        assert!(admission.admit_dns(&opt).is_none());
+        let stats = admission.stats();
+        assert_eq!(stats.rejected_over_total, 2);
+        assert_eq!(stats.rejected_over_client, 0);
+        assert_eq!(stats.rejected_rate, 0);
+        assert_eq!(stats.rejected_dns, 1);
+        assert_eq!(stats.dns_in_flight, 0);
+
+        opt.max_connections = None;
+        assert_eq!(
+            admission.admit(&opt, client(1)).err(),
+            Some(Refusal::OverClient)
+        );
+        let third = admission.admit(&opt, client(3)).unwrap();
+        drop(third);
+        assert_eq!(admission.admit(&opt, client(3)).err(), Some(Refusal::Rate));
+        let stats = admission.stats();
+        assert_eq!(stats.rejected_over_client, 1);
+        assert_eq!(stats.rejected_rate, 1);
+        assert_eq!(stats.active_connections, 2);
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use dashmap::DashMap;
+use event_listener::Event;
+use serde::{Deserialize, Serialize};
+
+/// How a proxied connection reaches its destination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Route {
+    Tunnel,
+    Direct,
+}
+
+/// One open proxied connection, as reported over the stats RPC.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub client: SocketAddr,
+    pub destination: String,
+    pub route: Route,
+    /// The routing rule that picked the route.
+    pub rule: String,
+    /// Unix timestamp of when the connection was opened.
+    pub started: u64,
+    pub sent_bytes: u64,
+    pub recv_bytes: u64,
+}
+
+struct Entry {
+    client: SocketAddr,
+    destination: String,
+    route: Route,
+    rule: String,
+    started: u64,
+    sent: AtomicU64,
+    recv: AtomicU64,
+    closed: AtomicBool,
+    on_close: Event,
+}
+
+/// Every open relay from the local proxies and port forwards, so that they can be listed and closed over the stats RPC.
+#[derive(Default)]
+pub struct Connections {
+    next_id: AtomicU64,
+    open: DashMap<u64, Arc<Entry>>,
+}
+
+impl Connections {
+    /// Adds a connection to the table. It stays listed until the returned handle is dropped.
+    pub fn register(
+        self: &Arc<Self>,
+        client: SocketAddr,
+        destination: String,
+        route: Route,
+        rule: String,
+    ) -> TrackedConnection {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let entry = Arc::new(Entry {
+            client,
+            destination,
+            route,
+            rule,
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            sent: AtomicU64::new(0),
+            recv: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            on_close: Event::new(),
+        });
+        self.open.insert(id, entry.clone());
+        TrackedConnection {
+            table: self.clone(),
+            id,
+            entry,
+        }
+    }
+
+    /// All open connections, oldest first.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut list: Vec<ConnectionInfo> = self
+            .open
+            .iter()
+            .map(|item| {
+                let entry = item.value();
+                ConnectionInfo {
+                    id: *item.key(),
+                    client: entry.client,
+                    destination: entry.destination.clone(),
+                    route: entry.route,
+                    rule: entry.rule.clone(),
+                    started: entry.started,
+                    sent_bytes: entry.sent.load(Ordering::Relaxed),
+                    recv_bytes: entry.recv.load(Ordering::Relaxed),
+                }
+            })
+            .collect();
+        list.sort_unstable_by_key(|info| info.id);
+        list
+    }
+
+    /// Closes the connection with the given ID, returning whether there was one.
+    pub fn close(&self, id: u64) -> bool {
+        let Some(entry) = self.open.get(&id).map(|entry| entry.clone()) else {
+            return false;
+        };
+        entry.closed.store(true, Ordering::SeqCst);
+        entry.on_close.notify(usize::MAX);
+        true
+    }
+}
+
+/// A connection's place in the [`Connections`] table, which counts its bytes and tells it when to close.
+pub struct TrackedConnection {
+    table: Arc<Connections>,
+    id: u64,
+    entry: Arc<Entry>,
+}
+
+impl TrackedConnection {
+    pub fn add_sent(&self, n: usize) {
+        self.entry.sent.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_recv(&self, n: usize) {
+        self.entry.recv.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Waits until the connection is closed through [`Connections::close`].
+    pub async fn closed(&self) {
+        loop {
+            let listener = self.entry.on_close.listen();
+            if self.entry.closed.load(Ordering::SeqCst) {
+                return;
+            }
+            listener.await;
+        }
+    }
+}
+
+impl Drop for TrackedConnection {
+    fn drop(&mut self) {
+        self.table.open.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use smol::future::FutureExt;
+
+    use super::*;
+
+    fn register(table: &Arc<Connections>, destination: &str) -> TrackedConnection {
+        table.register(
+            "127.0.0.1:50000".parse().unwrap(),
+            destination.into(),
+            Route::Tunnel,
+            "default".into(),
+        )
+    }
+
+    #[test]
+    fn registered_connections_are_listed_until_dropped() {
+        let table = Arc::new(Connections::default());
+        let first = register(&table, "example.com:443");
+        let second = register(&table, "example.net:80");
+        first.add_sent(100);
+        first.add_recv(2000);
+        second.add_sent(5);
+
+        let list = table.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].destination, "example.com:443");
+        assert_eq!((list[0].sent_bytes, list[0].recv_bytes), (100, 2000));
+        assert_eq!(list[0].route, Route::Tunnel);
+        assert_eq!(list[0].rule, "default");
+        assert_eq!(list[1].destination, "example.net:80");
+        assert_eq!((list[1].sent_bytes, list[1].recv_bytes), (5, 0));
+        assert!(list[0].id < list[1].id);
+
+        drop(first);
+        let list = table.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].destination, "example.net:80");
+        drop(second);
+        assert!(table.list().is_empty());
+    }
+
+    #[test]
+    fn close_wakes_only_that_connection() {
+        let table = Arc::new(Connections::default());
+        let closing = register(&table, "example.com:443");
+        let staying = register(&table, "example.net:80");
+        let id = table.list()[0].id;
+
+        assert!(table.close(id));
+        smol::block_on(closing.closed().or(async {
+            smol::Timer::after(Duration::from_secs(5)).await;
+            panic!("closed connection was not woken")
+        }));
+        let woken = async {
+            staying.closed().await;
+            true
+        }
+        .or(async {
+            smol::Timer::after(Duration::from_millis(100)).await;
+            false
+        });
+        assert!(!smol::block_on(woken));
+        assert!(!table.close(id + 100));
+        // closing only asks the relay to stop; the entry goes away with its handle
+        assert_eq!(table.list().len(), 2);
+        drop(closing);
+        assert!(!table.close(id));
+    }
+}
@@ -1,3 +1,4 @@
+use anyhow::Context;
 use sillad::Pipe;
 use smol::{
     channel::{Receiver, Sender},
@@ -10,20 +11,33 @@ use std::net::SocketAddr;
 use std::time::Duration;
 use std::{sync::Arc, time::Instant};
 
-use super::ConnectContext;
+use super::{acl::ListenerKind, ConnectContext};
 
 /// Handle DNS requests from localhost
 pub async fn dns_loop(ctx: ConnectContext, addr: SocketAddr) -> anyhow::Result<()> {
-    let socket = smol::net::UdpSocket::bind(addr).await?;
+    let socket = smol::net::UdpSocket::bind(addr)
+        .await
+        .context("cannot bind dns")?;
     let mut buf = [0; 2048];
-    let pool = Arc::new(DnsPool::new(ctx));
+    let pool = Arc::new(DnsPool::new(ctx.clone()));
     log::debug!("DNS loop started");
     loop {
         let (n, c_addr) = socket.recv_from(&mut buf).await?;
+        if !ctx.permit(ListenerKind::Dns, c_addr) {
+            continue;
+        }
+        let Some(admitted) = ctx.admission.admit_dns(&ctx.opt()) else {
+            log::debug!(
+                "too many DNS queries in flight; dropping one from {}",
+                c_addr
+            );
+            continue;
+        };
         let buff = buf[..n].to_vec();
         let socket = socket.clone();
         let pool = pool.clone();
         smolscale::spawn(async move {
+            let _admitted = admitted;
             let fut = || async {
                 socket
                     .send_to(&pool.request(&buff).await?, c_addr)
@@ -0,0 +1,245 @@
+use std::{net::IpAddr, time::Duration};
+
+use smol::{channel::Sender, process::Command, Task};
+use smol_timeout::TimeoutExt;
+
+use crate::config::ConnectOpt;
+
+/// What hook commands are told about a tunnel connection.
+#[derive(Clone, Debug)]
+pub struct HookConn {
+    pub protocol: String,
+    pub bridge: String,
+    pub exit: IpAddr,
+    pub exit_country: String,
+    pub exit_city: String,
+}
+
+impl HookConn {
+    fn env(&self, prefix: &str) -> Vec<(String, String)> {
+        vec![
+            (format!("GEPH_{prefix}PROTOCOL"), self.protocol.clone()),
+            (format!("GEPH_{prefix}BRIDGE"), self.bridge.clone()),
+            (format!("GEPH_{prefix}EXIT"), self.exit.to_string()),
+            (
+                format!("GEPH_{prefix}EXIT_COUNTRY"),
+                self.exit_country.clone(),
+            ),
+            (format!("GEPH_{prefix}EXIT_CITY"), self.exit_city.clone()),
+        ]
+    }
+}
+
+/// A hook event, the command to run for it, and the environment to run it with.
+type HookRun = (&'static str, String, Vec<(String, String)>);
+
+/// How long a hook command may run before it is killed.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many events may wait for the hook commands before them to finish. Any more are dropped.
+const MAX_PENDING_HOOKS: usize = 16;
+
+/// The user commands given by `--on-connect`, `--on-disconnect` and `--on-exit-change`. Commands run one at a time, in the order the events happened, without holding up whoever reported the event.
+pub struct Hooks {
+    on_connect: Option<String>,
+    on_disconnect: Option<String>,
+    on_exit_change: Option<String>,
+    send_run: Sender<HookRun>,
+    _runner: Task<()>,
+}
+
+impl Hooks {
+    pub fn new(opt: &ConnectOpt) -> Self {
+        let (send_run, recv_run) = smol::channel::bounded::<HookRun>(MAX_PENDING_HOOKS);
+        let runner = smolscale::spawn(async move {
+            while let Ok((event, command, env)) = recv_run.recv().await {
+                if let Err(err) = run_hook(event, &command, env, HOOK_TIMEOUT).await {
+                    log::warn!("{} hook failed: {:?}", event, err);
+                }
+            }
+        });
+        Self {
+            on_connect: opt.on_connect.clone(),
+            on_disconnect: opt.on_disconnect.clone(),
+            on_exit_change: opt.on_exit_change.clone(),
+            send_run,
+            _runner: runner,
+        }
+    }
+
+    /// The tunnel went from connecting to connected.
+    pub fn connected(&self, conn: &HookConn) {
+        self.fire("connect", &self.on_connect, conn.env(""));
+    }
+
+    /// The tunnel went from connected back to connecting. `conn` is the connection that was lost.
+    pub fn disconnected(&self, conn: &HookConn) {
+        self.fire("disconnect", &self.on_disconnect, conn.env(""));
+    }
+
+    /// The tunnel came up through a different exit than last time.
+    pub fn exit_changed(&self, previous: &HookConn, conn: &HookConn) {
+        let mut env = conn.env("");
+        env.extend(previous.env("PREVIOUS_"));
+        self.fire("exit_change", &self.on_exit_change, env);
+    }
+
+    fn fire(&self, event: &'static str, command: &Option<String>, env: Vec<(String, String)>) {
+        if let Some(command) = command {
+            if self
+                .send_run
+                .try_send((event, command.clone(), env))
+                .is_err()
+            {
+                log::warn!(
+                    "dropping {} hook, since {} earlier hooks have yet to run",
+                    event,
+                    self.send_run.len()
+                );
+            }
+        }
+    }
+}
+
+/// Runs a hook command through the shell, killing it if it runs for longer than `timeout`.
+async fn run_hook(
+    event: &'static str,
+    command: &str,
+    env: Vec<(String, String)>,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    log::debug!("running {} hook: {}", event, command);
+    #[cfg(windows)]
+    let mut cmd = {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    };
+    #[cfg(not(windows))]
+    let mut cmd = {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+    let mut child = cmd
+        .env("GEPH_EVENT", event)
+        .envs(env)
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let Some(status) = child.status().timeout(timeout).await else {
+        child.kill()?;
+        anyhow::bail!("killed after running for {:?}", timeout)
+    };
+    let status = status?;
+    anyhow::ensure!(status.success(), "exited with {}", status);
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::Instant;
+
+    use structopt::StructOpt;
+
+    use super::*;
+
+    fn conn(exit: &str, city: &str) -> HookConn {
+        HookConn {
+            protocol: "sosistab3".into(),
+            bridge: "203.0.113.1:1234".into(),
+            exit: exit.parse().unwrap(),
+            exit_country: "CA".into(),
+            exit_city: city.into(),
+        }
+    }
+
+    /// Waits for a hook to write its environment to `path`, and parses it.
+    fn read_env(path: &std::path::Path) -> Vec<String> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            // the file only counts once the hook finished writing it
+            if let Ok(env) = std::fs::read_to_string(path) {
+                if env.contains("GEPH_DONE=1") {
+                    return env.lines().map(String::from).collect();
+                }
+            }
+            assert!(Instant::now() < deadline, "hook never ran");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn hooks_get_the_event_and_exit() {
+        let env_file = |name: &str| {
+            std::env::temp_dir().join(format!("geph4-hook-test-{}-{}", std::process::id(), name))
+        };
+        let command = |name: &str| {
+            format!(
+                "env > {}.tmp && echo GEPH_DONE=1 >> {0}.tmp && mv {0}.tmp {0}",
+                env_file(name).display()
+            )
+        };
+        let opt = crate::config::ConnectOpt::from_iter_safe([
+            "geph4-client".to_string(),
+            "--on-connect".into(),
+            command("connect"),
+            "--on-exit-change".into(),
+            command("exit_change"),
+        ])
+        .unwrap();
+        let hooks = Hooks::new(&opt);
+        let previous = conn("192.0.2.1", "Montreal");
+        let current = conn("192.0.2.2", "Toronto");
+        hooks.connected(&current);
+        hooks.exit_changed(&previous, &current);
+
+        let env = read_env(&env_file("connect"));
+        for var in [
+            "GEPH_EVENT=connect",
+            "GEPH_EXIT=192.0.2.2",
+            "GEPH_EXIT_COUNTRY=CA",
+            "GEPH_EXIT_CITY=Toronto",
+            "GEPH_PROTOCOL=sosistab3",
+        ] {
+            assert!(
+                env.iter().any(|line| line == var),
+                "{} not in {:?}",
+                var,
+                env
+            );
+        }
+        let env = read_env(&env_file("exit_change"));
+        for var in [
+            "GEPH_EVENT=exit_change",
+            "GEPH_EXIT=192.0.2.2",
+            "GEPH_EXIT_CITY=Toronto",
+            "GEPH_PREVIOUS_EXIT=192.0.2.1",
+            "GEPH_PREVIOUS_EXIT_CITY=Montreal",
+        ] {
+            assert!(
+                env.iter().any(|line| line == var),
+                "{} not in {:?}",
+                var,
+                env
+            );
+        }
+        for name in ["connect", "exit_change"] {
+            std::fs::remove_file(env_file(name)).unwrap();
+        }
+    }
+
+    #[test]
+    fn hooks_are_killed_after_the_timeout() {
+        let start = Instant::now();
+        let err = smolscale::block_on(run_hook(
+            "connect",
+            "sleep 30",
+            vec![],
+            Duration::from_millis(200),
+        ))
+        .unwrap_err();
+        assert!(err.to_string().contains("killed"), "{}", err);
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,90 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use futures_util::{FutureExt, TryFutureExt};
+use hyper::StatusCode;
+
+use crate::socks2http::{Address, ConnectionGuard, Login, OpenError, Upstreams};
+
+use super::{
+    proxy_users::UserCounter,
+    socks5::{open_upstream, relay, Upstream},
+    tunnel::OpenFailure,
+    ConnectContext,
+};
+
+/// Sends the HTTP proxy's requests wherever the routing rules say, just like the SOCKS5 server does with its own.
+pub struct HttpUpstreams(pub ConnectContext);
+
+#[async_trait]
+impl Upstreams for HttpUpstreams {
+    type User = Option<Arc<UserCounter>>;
+    type Conn = Upstream;
+
+    fn login(&self, login: Option<&Login>) -> Option<Self::User> {
+        let users = &self.0.users;
+        if !users.required() {
+            return Some(None);
+        }
+        let login = login?;
+        users.check(&login.username, &login.password).map(Some)
+    }
+
+    async fn open(&self, addr: &Address) -> Result<Upstream, OpenError> {
+        let (host, ip, port) = match addr {
+            Address::SocketAddress(addr) => (addr.ip().to_string(), Some(addr.ip()), addr.port()),
+            Address::DomainNameAddress(host, port) => (host.clone(), host.parse().ok(), *port),
+        };
+        open_upstream(&self.0, &host, ip, port, self.0.opt().exclude_prc)
+            .await
+            .map_err(|err| {
+                log::debug!("http proxy cannot open {}: {:?}", addr, err);
+                open_error(&err, addr)
+            })
+    }
+
+    fn relay<S>(
+        &self,
+        client: S,
+        client_addr: SocketAddr,
+        addr: &Address,
+        conn: Upstream,
+        user: Self::User,
+        guard: ConnectionGuard,
+    ) where
+        S: futures_util::AsyncRead + futures_util::AsyncWrite + Send + Unpin + 'static,
+    {
+        let ctx = self.0.clone();
+        let destination = addr.to_string();
+        self.0.relays.spawn(
+            async move { relay(&ctx, client, client_addr, destination, conn, user).await }
+                .map_err(|e| log::debug!("http relay died with: {:?}", e))
+                .map(move |_| drop(guard)),
+        )
+    }
+}
+
+/// Tells an HTTP client why its connection could not be opened: 503 while the tunnel is down, 504 when the destination did not answer in time, 403 when a routing rule rejected it, and 502 for everything else.
+fn open_error(err: &anyhow::Error, addr: &Address) -> OpenError {
+    let (status, message) = match OpenFailure::of(err) {
+        OpenFailure::TunnelDown => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Geph is reconnecting; try again later".to_string(),
+        ),
+        OpenFailure::TimedOut => (
+            StatusCode::GATEWAY_TIMEOUT,
+            format!("Timed out connecting to {}", addr),
+        ),
+        OpenFailure::Rejected => (
+            StatusCode::FORBIDDEN,
+            format!("{} is blocked by a routing rule", addr),
+        ),
+        OpenFailure::Unreachable => (StatusCode::BAD_GATEWAY, format!("Cannot reach {}", addr)),
+        OpenFailure::Refused => (
+            StatusCode::BAD_GATEWAY,
+            format!("{} refused the connection", addr),
+        ),
+        OpenFailure::Other => (StatusCode::BAD_GATEWAY, format!("Relay failed to {}", addr)),
+    };
+    OpenError { status, message }
+}
@@ -0,0 +1,77 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::Context;
+use futures_util::{FutureExt, TryFutureExt};
+
+use super::{
+    acl::ListenerKind, admission::Admitted, http_proxy::HttpUpstreams, socks4, socks5,
+    ConnectContext,
+};
+
+/// Serves SOCKS4/4a, SOCKS5 and HTTP proxy clients on one port, telling them apart by the first byte they send.
+pub async fn mixed_loop(ctx: ConnectContext, addr: SocketAddr) -> anyhow::Result<()> {
+    let listener = smol::net::TcpListener::bind(addr)
+        .await
+        .context("cannot bind mixed")?;
+    let http = Arc::new(HttpUpstreams(ctx.clone()));
+    log::debug!("mixed started");
+    loop {
+        let (client, client_addr) = listener.accept().await.context("cannot accept mixed")?;
+        if !ctx.permit(ListenerKind::Mixed, client_addr) {
+            ctx.relays.spawn(
+                refuse(client)
+                    .map_err(|e| log::debug!("mixed refusal died with: {:?}", e))
+                    .map(drop),
+            );
+            continue;
+        }
+        let Some(admitted) = ctx.admit(client_addr) else {
+            continue;
+        };
+        let exclude_prc = ctx.opt().exclude_prc;
+        let http = http.clone();
+        ctx.relays.spawn(
+            dispatch(
+                ctx.clone(),
+                client,
+                client_addr,
+                http,
+                exclude_prc,
+                admitted,
+            )
+            .map_err(|e| log::debug!("mixed handler died with: {:?}", e))
+            .map(drop),
+        )
+    }
+}
+
+/// Tells a refused HTTP client why with a 403, while SOCKS clients, which have no way to hear it, are just hung up on.
+async fn refuse(client: smol::net::TcpStream) -> anyhow::Result<()> {
+    let mut first = [0u8];
+    if client.peek(&mut first).await? > 0 && first[0] != 4 && first[0] != 5 {
+        crate::socks2http::serve_forbidden(client).await?;
+    }
+    Ok(())
+}
+
+async fn dispatch(
+    ctx: ConnectContext,
+    client: smol::net::TcpStream,
+    client_addr: SocketAddr,
+    http: Arc<HttpUpstreams>,
+    exclude_prc: bool,
+    admitted: Admitted,
+) -> anyhow::Result<()> {
+    // peeking leaves the byte in place for whichever handler takes the connection
+    let mut first = [0u8];
+    anyhow::ensure!(
+        client.peek(&mut first).await? > 0,
+        "closed before sending anything"
+    );
+    match first[0] {
+        4 => socks4::handle_socks4(ctx, client, exclude_prc).await,
+        5 => socks5::handle_socks5(ctx, client, exclude_prc).await,
+        // CONNECT tunnels outlive the HTTP connection, so they share its place under the limits
+        _ => crate::socks2http::serve_http(client, client_addr, http, Arc::new(admitted)).await,
+    }
+}
@@ -1,31 +1,58 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
-use futures_util::AsyncReadExt;
+use anyhow::Context;
+use futures_util::{FutureExt, TryFutureExt};
 
-use super::ConnectContext;
+use super::{
+    acl::ListenerKind,
+    socks5::{dest_addr, open_upstream, relay},
+    ConnectContext,
+};
 
 /// Forwards ports using a particular description.
 pub async fn port_forwarder(ctx: ConnectContext, desc: String) -> anyhow::Result<()> {
     let exploded = desc.split(":::").collect::<Vec<_>>();
-    let listen_addr: SocketAddr = exploded[0].parse().expect("invalid port forwarding syntax");
+    anyhow::ensure!(exploded.len() == 2, "invalid port forwarding syntax");
+    let listen_addr: SocketAddr = exploded[0]
+        .parse()
+        .context("invalid port forwarding syntax")?;
+    let (remote_host, remote_port) = exploded[1]
+        .rsplit_once(':')
+        .context("invalid port forwarding syntax")?;
+    let remote_port: u16 = remote_port
+        .parse()
+        .context("invalid port forwarding syntax")?;
+    let remote_ip: Option<IpAddr> = remote_host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok();
+    let remote_host = remote_host.to_string();
     let listener = smol::net::TcpListener::bind(listen_addr)
         .await
-        .expect("could not listen for port forwarding");
+        .context("could not listen for port forwarding")?;
     loop {
-        let (conn, _) = listener.accept().await?;
+        let (conn, client_addr) = listener.accept().await?;
+        if !ctx.permit(ListenerKind::Forward, client_addr) {
+            continue;
+        }
+        let Some(admitted) = ctx.admit(client_addr) else {
+            continue;
+        };
 
-        let remote_addr = exploded[1].to_owned();
         let ctx = ctx.clone();
-        smolscale::spawn(async move {
-            let remote = ctx.tunnel.connect_stream(&remote_addr).await.ok()?;
-            let (read_remote, write_remote) = remote.split();
-            smol::future::race(
-                smol::io::copy(read_remote, conn.clone()),
-                smol::io::copy(conn, write_remote),
-            )
-            .await
-            .ok()
-        })
-        .detach();
+        let remote_host = remote_host.clone();
+        let exclude_prc = ctx.opt().exclude_prc;
+        ctx.relays.clone().spawn(
+            async move {
+                // port forwards go wherever the routing rules say, just like proxied connections
+                let upstream =
+                    open_upstream(&ctx, &remote_host, remote_ip, remote_port, exclude_prc).await?;
+                let destination = dest_addr(&remote_host, remote_ip, remote_port);
+                relay(&ctx, conn, client_addr, destination, upstream, None).await
+            }
+            .map_err(|e| log::debug!("port forward died with: {:?}", e))
+            .map(move |_| drop(admitted)),
+        );
     }
 }
@@ -0,0 +1,159 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Context;
+use dashmap::DashMap;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+
+/// How much one proxy user has sent and received, as reported over the stats RPC.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UserStats {
+    pub sent_bytes: u64,
+    pub recv_bytes: u64,
+}
+
+/// Live byte counters for one proxy user.
+#[derive(Default)]
+pub struct UserCounter {
+    sent: AtomicU64,
+    recv: AtomicU64,
+}
+
+impl UserCounter {
+    pub fn add_sent(&self, n: usize) {
+        self.sent.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_recv(&self, n: usize) {
+        self.recv.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// Logins read from a credentials file, which are not in effect until given to [`ProxyUsers::set`].
+pub struct Logins(Option<HashMap<String, String>>);
+
+/// The logins that the local proxies accept, loaded from the `--proxy-credentials` file, and how much each user has used them. With no file, the proxies accept anyone.
+#[derive(Default)]
+pub struct ProxyUsers {
+    logins: RwLock<Option<HashMap<String, String>>>,
+    counters: DashMap<String, Arc<UserCounter>>,
+}
+
+impl ProxyUsers {
+    /// Reads the logins from a credentials file, or no logins at all if there is none, without putting them in effect yet.
+    pub fn read(path: Option<&Path>) -> anyhow::Result<Logins> {
+        Ok(Logins(path.map(read_logins).transpose()?))
+    }
+
+    /// Puts logins from [`ProxyUsers::read`] in effect, replacing the old ones.
+    pub fn set(&self, logins: Logins) {
+        *self.logins.write() = logins.0;
+    }
+
+    /// Whether the proxies require a login at all.
+    pub fn required(&self) -> bool {
+        self.logins.read().is_some()
+    }
+
+    /// Checks a login, returning the user's counters if it is valid.
+    pub fn check(&self, username: &str, password: &str) -> Option<Arc<UserCounter>> {
+        let logins = self.logins.read();
+        let expected = logins.as_ref()?.get(username)?;
+        // compared in constant time, so that timing does not give away how much of a guess was right
+        if !bool::from(expected.as_bytes().ct_eq(password.as_bytes())) {
+            return None;
+        }
+        Some(self.counters.entry(username.into()).or_default().clone())
+    }
+
+    /// How much each user has sent and received since the daemon started.
+    pub fn stats(&self) -> BTreeMap<String, UserStats> {
+        self.counters
+            .iter()
+            .map(|entry| {
+                (
+                    entry.key().clone(),
+                    UserStats {
+                        sent_bytes: entry.sent.load(Ordering::Relaxed),
+                        recv_bytes: entry.recv.load(Ordering::Relaxed),
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+fn read_logins(path: &Path) -> anyhow::Result<HashMap<String, String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("cannot read proxy credentials from {}", path.display()))?;
+    parse_logins(&contents)
+        .with_context(|| format!("invalid proxy credentials in {}", path.display()))
+}
+
+/// Parses `username:password` lines, skipping blank lines and `#` comments.
+fn parse_logins(contents: &str) -> anyhow::Result<HashMap<String, String>> {
+    let mut logins = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (username, password) = line
+            .split_once(':')
+            .with_context(|| format!("line {} is not of the form username:password", i + 1))?;
+        anyhow::ensure!(
+            (1..=255).contains(&username.len()) && (1..=255).contains(&password.len()),
+            "line {} has an empty or overlong username or password",
+            i + 1
+        );
+        logins.insert(username.to_string(), password.to_string());
+    }
+    Ok(logins)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_lines() {
+        let logins =
+            parse_logins("# office\n\nalice:secret\n  bob:pa:ss  \n# carol:nope\nalice:newer\n")
+                .unwrap();
+        assert_eq!(logins.len(), 2);
+        assert_eq!(logins["alice"], "newer");
+        // only the first colon separates the username from the password
+        assert_eq!(logins["bob"], "pa:ss");
+    }
+
+    #[test]
+    fn reject_bad_lines() {
+        let err = parse_logins("alice:secret\nbob\n").unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+        assert!(parse_logins(":secret").is_err());
+        assert!(parse_logins("alice:").is_err());
+        assert!(parse_logins(&format!("alice:{}", "x".repeat(256))).is_err());
+        assert!(parse_logins("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn check_logins() {
+        let users = ProxyUsers::default();
+        assert!(!users.required());
+        *users.logins.write() = Some(parse_logins("alice:secret").unwrap());
+        assert!(users.required());
+        assert!(users.check("alice", "secret").is_some());
+        assert!(users.check("alice", "secreT").is_none());
+        assert!(users.check("alice", "secret2").is_none());
+        assert!(users.check("alice", "").is_none());
+        assert!(users.check("bob", "secret").is_none());
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use event_listener::Event;
+use parking_lot::Mutex;
+use smol::future::FutureExt;
+use smolscale::reaper::TaskReaper;
+
+/// Keeps track of in-flight relays, so that shutting down can wait for them to finish before cutting them off.
+pub struct Relays {
+    reaper: Mutex<Option<TaskReaper<()>>>,
+    active: AtomicUsize,
+    idle: Event,
+}
+
+impl Default for Relays {
+    fn default() -> Self {
+        Self {
+            reaper: Mutex::new(Some(TaskReaper::new())),
+            active: AtomicUsize::new(0),
+            idle: Event::new(),
+        }
+    }
+}
+
+impl Relays {
+    /// Spawns a relay in the background. Once the relays are closed, the relay is dropped instead.
+    pub fn spawn(self: &Arc<Self>, relay: impl Future<Output = ()> + Send + 'static) {
+        let reaper = self.reaper.lock();
+        if let Some(reaper) = reaper.as_ref() {
+            self.active.fetch_add(1, Ordering::SeqCst);
+            let this = self.clone();
+            reaper.attach(smolscale::spawn(async move {
+                scopeguard::defer!({
+                    if this.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        this.idle.notify(usize::MAX);
+                    }
+                });
+                relay.await
+            }));
+        }
+    }
+
+    /// Stops accepting new relays, waits until either every relay has finished or the deadline passes, and then cancels whatever is left. Returns how many relays were cancelled.
+    pub async fn close(&self, deadline: Instant) -> usize {
+        let reaper = self.reaper.lock().take();
+        loop {
+            let listener = self.idle.listen();
+            if self.active.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+            let timed_out = async {
+                listener.await;
+                false
+            }
+            .or(async {
+                smol::Timer::at(deadline).await;
+                true
+            })
+            .await;
+            if timed_out {
+                break;
+            }
+        }
+        let remaining = self.active.load(Ordering::SeqCst);
+        drop(reaper);
+        remaining
+    }
+}
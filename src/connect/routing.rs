@@ -0,0 +1,363 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv6Addr},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use parking_lot::RwLock;
+use psl::Psl;
+use serde::{Deserialize, Serialize};
+
+use crate::{china, cidr::Cidr};
+
+/// What to do with a proxied connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Proxy,
+    Direct,
+    Reject,
+}
+
+/// Which way a destination goes, and the rule that decided it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RouteDecision {
+    pub action: Action,
+    /// The matching line of the rules file, like `DOMAIN-SUFFIX,example.com,DIRECT`, or one of the built-in rules `private`, `exclude_prc` and `default`.
+    pub rule: String,
+}
+
+/// The error for connections refused by a `REJECT` rule.
+#[derive(Debug, thiserror::Error)]
+#[error("rejected by routing rule {0}")]
+pub struct Rejected(pub String);
+
+#[derive(Clone, Debug)]
+enum Matcher {
+    Domain(String),
+    DomainSuffix(String),
+    DomainKeyword(String),
+    Cidr(Cidr),
+    Region(Region),
+    Any,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Region {
+    China,
+    Private,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    matcher: Matcher,
+    action: Action,
+    line: String,
+}
+
+/// Rules read from a `--routing-rules` file, which are not in effect until given to [`Router::set`].
+pub struct Rules(Vec<Rule>);
+
+/// The routing rules from the `--routing-rules` file, shared by every proxy frontend. Destinations that no rule matches fall back to the built-in behavior: private addresses go direct, and so do Chinese ones under `--exclude-prc`; everything else goes through the tunnel.
+#[derive(Default)]
+pub struct Router {
+    rules: RwLock<Arc<Vec<Rule>>>,
+}
+
+impl Router {
+    /// Reads the rules from a file, or no rules at all if there is none, without putting them in effect yet.
+    pub fn read(path: Option<&Path>) -> anyhow::Result<Rules> {
+        Ok(Rules(path.map(read_rules).transpose()?.unwrap_or_default()))
+    }
+
+    /// Puts rules from [`Router::read`] in effect, replacing the old ones.
+    pub fn set(&self, rules: Rules) {
+        *self.rules.write() = Arc::new(rules.0);
+    }
+
+    /// Decides where a connection to `host`, which is an IP literal when `ip` is given, should go.
+    pub fn route(&self, host: &str, ip: Option<IpAddr>, exclude_prc: bool) -> RouteDecision {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let rules = self.rules.read().clone();
+        if let Some(rule) = rules.iter().find(|rule| rule.matcher.matches(&host, ip)) {
+            return RouteDecision {
+                action: rule.action,
+                rule: rule.line.clone(),
+            };
+        }
+        let builtin = |action, rule: &str| RouteDecision {
+            action,
+            rule: rule.into(),
+        };
+        if Region::Private.contains(&host, ip) {
+            builtin(Action::Direct, "private")
+        } else if exclude_prc && Region::China.contains(&host, ip) {
+            builtin(Action::Direct, "exclude_prc")
+        } else {
+            builtin(Action::Proxy, "default")
+        }
+    }
+}
+
+/// Whether `host`, which is an IP literal when `ip` is given, is on the local network.
+pub fn is_private(host: &str, ip: Option<IpAddr>) -> bool {
+    Region::Private.contains(&host.trim_end_matches('.').to_ascii_lowercase(), ip)
+}
+
+impl Matcher {
+    fn matches(&self, host: &str, ip: Option<IpAddr>) -> bool {
+        match self {
+            // domain rules never match IP literals, and CIDR rules never match domains, since resolving them here would leak the lookup
+            Matcher::Domain(domain) => ip.is_none() && host == domain,
+            Matcher::DomainSuffix(suffix) => {
+                ip.is_none()
+                    && (host == suffix
+                        || host
+                            .strip_suffix(suffix.as_str())
+                            .is_some_and(|rest| rest.ends_with('.')))
+            }
+            Matcher::DomainKeyword(keyword) => ip.is_none() && host.contains(keyword.as_str()),
+            Matcher::Cidr(cidr) => ip.is_some_and(|ip| cidr.contains(ip)),
+            Matcher::Region(region) => region.contains(host, ip),
+            Matcher::Any => true,
+        }
+    }
+}
+
+impl Region {
+    fn contains(self, host: &str, ip: Option<IpAddr>) -> bool {
+        match (self, ip) {
+            (Region::Private, Some(IpAddr::V4(v4))) => v4.is_private() || v4.is_loopback(),
+            (Region::Private, Some(IpAddr::V6(v6))) => is_private_ipv6(v6),
+            // names without a public suffix, like "localhost" or "printer.lan", can only be local
+            (Region::Private, None) => !psl::List
+                .suffix(host.as_bytes())
+                .map(|suf| suf.typ().is_some())
+                .unwrap_or_default(),
+            (Region::China, Some(IpAddr::V4(v4))) => china::is_chinese_ip(v4),
+            (Region::China, Some(IpAddr::V6(v6))) => china::is_chinese_ipv6(v6),
+            (Region::China, None) => china::is_chinese_host(host),
+        }
+    }
+}
+
+/// Loopback, link-local (fe80::/10) and unique local (fc00::/7) IPv6 addresses, plus IPv4-mapped private addresses.
+fn is_private_ipv6(ip: Ipv6Addr) -> bool {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return v4.is_private() || v4.is_loopback();
+    }
+    let first = ip.segments()[0];
+    ip.is_loopback() || ip.is_unspecified() || first & 0xffc0 == 0xfe80 || first & 0xfe00 == 0xfc00
+}
+
+fn read_rules(path: &Path) -> anyhow::Result<Vec<Rule>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("cannot read routing rules from {}", path.display()))?;
+    parse_rules(&contents).with_context(|| format!("invalid routing rules in {}", path.display()))
+}
+
+/// Parses `TYPE,VALUE,ACTION` lines, plus a catch-all `MATCH,ACTION`, skipping blank lines and `#` comments.
+fn parse_rules(contents: &str) -> anyhow::Result<Vec<Rule>> {
+    let mut rules = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let rule = parse_rule(line).with_context(|| format!("line {}: {:?}", i + 1, line))?;
+        rules.push(rule);
+    }
+    Ok(rules)
+}
+
+fn parse_rule(line: &str) -> anyhow::Result<Rule> {
+    let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+    let (kind, value, action) = match fields.as_slice() {
+        [kind, action] => (*kind, "", *action),
+        [kind, value, action] => (*kind, *value, *action),
+        _ => anyhow::bail!("expected TYPE,VALUE,ACTION or MATCH,ACTION"),
+    };
+    let matcher = match (kind.to_ascii_uppercase().as_str(), fields.len()) {
+        ("MATCH", 2) => Matcher::Any,
+        ("DOMAIN", 3) => Matcher::Domain(domain(value)?),
+        ("DOMAIN-SUFFIX", 3) => Matcher::DomainSuffix(domain(value)?),
+        ("DOMAIN-KEYWORD", 3) => {
+            anyhow::ensure!(!value.is_empty(), "empty keyword");
+            Matcher::DomainKeyword(value.to_ascii_lowercase())
+        }
+        ("IP-CIDR", 3) => Matcher::Cidr(value.parse()?),
+        ("REGION", 3) => match value.to_ascii_uppercase().as_str() {
+            "CN" => Matcher::Region(Region::China),
+            "PRIVATE" => Matcher::Region(Region::Private),
+            _ => anyhow::bail!(
+                "unknown region {:?}; known regions are CN and PRIVATE",
+                value
+            ),
+        },
+        _ => anyhow::bail!("unknown rule type {:?}", kind),
+    };
+    let action = match action.to_ascii_uppercase().as_str() {
+        "PROXY" => Action::Proxy,
+        "DIRECT" => Action::Direct,
+        "REJECT" => Action::Reject,
+        _ => anyhow::bail!(
+            "unknown action {:?}; expected PROXY, DIRECT or REJECT",
+            action
+        ),
+    };
+    Ok(Rule {
+        matcher,
+        action,
+        line: line.to_string(),
+    })
+}
+
+fn domain(value: &str) -> anyhow::Result<String> {
+    let domain = value.trim_start_matches('.').trim_end_matches('.');
+    anyhow::ensure!(!domain.is_empty(), "empty domain");
+    Ok(domain.to_ascii_lowercase())
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Proxy => write!(f, "proxy"),
+            Action::Direct => write!(f, "direct"),
+            Action::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_rules(rules: &str) -> Router {
+        let router = Router::default();
+        *router.rules.write() = Arc::new(parse_rules(rules).unwrap());
+        router
+    }
+
+    fn route(router: &Router, host: &str, exclude_prc: bool) -> (Action, String) {
+        let decision = router.route(host, host.parse().ok(), exclude_prc);
+        (decision.action, decision.rule)
+    }
+
+    #[test]
+    fn parse_rule_types() {
+        let rules = parse_rules(
+            "# comment\n\n\
+             DOMAIN,Example.COM.,direct\n\
+             domain-suffix , .example.org , Reject\n\
+             DOMAIN-KEYWORD,Tracker,REJECT\n\
+             IP-CIDR,10.0.0.0/8,PROXY\n\
+             REGION,cn,DIRECT\n\
+             MATCH,PROXY\n",
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 6);
+        assert!(matches!(&rules[0].matcher, Matcher::Domain(d) if d == "example.com"));
+        assert_eq!(rules[0].action, Action::Direct);
+        assert!(matches!(&rules[1].matcher, Matcher::DomainSuffix(d) if d == "example.org"));
+        assert_eq!(rules[1].action, Action::Reject);
+        assert!(matches!(&rules[2].matcher, Matcher::DomainKeyword(k) if k == "tracker"));
+        assert!(matches!(rules[3].matcher, Matcher::Cidr(_)));
+        assert!(matches!(rules[4].matcher, Matcher::Region(Region::China)));
+        assert!(matches!(rules[5].matcher, Matcher::Any));
+        assert_eq!(rules[1].line, "domain-suffix , .example.org , Reject");
+    }
+
+    #[test]
+    fn reject_bad_rules() {
+        for bad in [
+            "DOMAIN,example.com",
+            "MATCH,example.com,PROXY",
+            "DOMAIN,,PROXY",
+            "DOMAIN-KEYWORD,,PROXY",
+            "IP-CIDR,10.0.0.0,PROXY",
+            "REGION,US,DIRECT",
+            "GEOIP,CN,DIRECT",
+            "DOMAIN,example.com,BLOCK",
+            "DOMAIN,example.com,PROXY,extra",
+        ] {
+            assert!(parse_rule(bad).is_err(), "{:?} parsed", bad);
+        }
+        let err = parse_rules("MATCH,PROXY\nbogus\n").unwrap_err();
+        assert!(format!("{:#}", err).contains("line 2"), "{:#}", err);
+    }
+
+    #[test]
+    fn domain_matching() {
+        let router = with_rules(
+            "DOMAIN,exact.com,REJECT\n\
+             DOMAIN-SUFFIX,example.org,DIRECT\n\
+             DOMAIN-KEYWORD,ads,REJECT\n",
+        );
+        assert_eq!(route(&router, "EXACT.com.", false).0, Action::Reject);
+        assert_eq!(route(&router, "www.exact.com", false).0, Action::Proxy);
+        assert_eq!(route(&router, "example.org", false).0, Action::Direct);
+        assert_eq!(route(&router, "a.b.example.org", false).0, Action::Direct);
+        // a suffix only matches at a label boundary
+        assert_eq!(route(&router, "badexample.org", false).0, Action::Proxy);
+        assert_eq!(route(&router, "myads.net", false).0, Action::Reject);
+    }
+
+    #[test]
+    fn address_rules_only_match_addresses() {
+        let router = with_rules(
+            "IP-CIDR,192.0.2.0/24,REJECT\n\
+             IP-CIDR,2001:db8::/32,DIRECT\n",
+        );
+        assert_eq!(route(&router, "192.0.2.9", false).0, Action::Reject);
+        assert_eq!(route(&router, "2001:db8::1", false).0, Action::Direct);
+        assert_eq!(route(&router, "198.51.100.1", false).0, Action::Proxy);
+        // domain rules never match IP literals
+        let router = with_rules("DOMAIN-KEYWORD,192,REJECT\n");
+        assert_eq!(route(&router, "192.0.2.9", false).0, Action::Proxy);
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let router = with_rules(
+            "DOMAIN,www.example.com,PROXY\n\
+             DOMAIN-SUFFIX,example.com,REJECT\n\
+             MATCH,DIRECT\n\
+             DOMAIN,other.com,REJECT\n",
+        );
+        assert_eq!(
+            route(&router, "www.example.com", false),
+            (Action::Proxy, "DOMAIN,www.example.com,PROXY".into())
+        );
+        assert_eq!(route(&router, "mail.example.com", false).0, Action::Reject);
+        assert_eq!(
+            route(&router, "other.com", false),
+            (Action::Direct, "MATCH,DIRECT".into())
+        );
+    }
+
+    #[test]
+    fn rules_take_precedence_over_builtins() {
+        let router = with_rules("IP-CIDR,10.1.0.0/16,PROXY\nREGION,PRIVATE,REJECT\n");
+        assert_eq!(route(&router, "10.1.2.3", false).0, Action::Proxy);
+        assert_eq!(route(&router, "10.2.0.1", false).0, Action::Reject);
+
+        let router = Router::default();
+        assert_eq!(
+            route(&router, "192.168.1.1", false),
+            (Action::Direct, "private".into())
+        );
+        assert_eq!(route(&router, "fe80::1", false).0, Action::Direct);
+        assert_eq!(route(&router, "printer.lan", false).0, Action::Direct);
+        assert_eq!(
+            route(&router, "114.114.114.114", true),
+            (Action::Direct, "exclude_prc".into())
+        );
+        assert_eq!(
+            route(&router, "114.114.114.114", false),
+            (Action::Proxy, "default".into())
+        );
+        assert_eq!(route(&router, "example.com", true).0, Action::Proxy);
+    }
+}
@@ -0,0 +1,285 @@
+use std::time::{Duration, Instant};
+
+use futures_util::{AsyncRead, AsyncWrite};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::ConnectContext;
+use crate::config::ConnectOpt;
+
+/// Bandwidth limits for traffic through the tunnel, in KiB/s. None means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthLimits {
+    /// All traffic to the tunnel, together.
+    pub upload: Option<u32>,
+    /// All traffic from the tunnel, together.
+    pub download: Option<u32>,
+    /// Each proxied connection, in each direction on its own.
+    pub per_connection: Option<u32>,
+}
+
+impl BandwidthLimits {
+    /// The limits that the options ask for.
+    pub fn of(opt: &ConnectOpt) -> Self {
+        Self {
+            upload: opt.upload_limit,
+            download: opt.download_limit,
+            per_connection: opt.per_connection_limit,
+        }
+    }
+
+    /// Writes the limits into the options.
+    pub fn apply(&self, opt: &mut ConnectOpt) {
+        opt.upload_limit = self.upload;
+        opt.download_limit = self.download;
+        opt.per_connection_limit = self.per_connection;
+    }
+}
+
+/// Which way bytes go through the tunnel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+/// A token bucket holding up to a second's worth of bytes. Taking more than it holds puts it in debt, which the taker and everyone after it wait out, so that big chunks are never starved by small ones.
+#[derive(Default)]
+pub struct TokenBucket {
+    /// The tokens left, and when they were last topped up.
+    state: Mutex<Option<(f64, Instant)>>,
+}
+
+impl TokenBucket {
+    /// Waits until `n` bytes may pass at `limit` KiB/s. Without a limit, they pass right away.
+    pub async fn take(&self, n: usize, limit: Option<u32>) {
+        let Some(limit) = limit else {
+            return;
+        };
+        let rate = limit as f64 * 1024.0;
+        let wait = {
+            let mut state = self.state.lock();
+            let now = Instant::now();
+            let (tokens, last) = state.get_or_insert((rate, now));
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * rate).min(rate);
+            *last = now;
+            *tokens -= n as f64;
+            Duration::from_secs_f64((-*tokens / rate).max(0.0))
+        };
+        if !wait.is_zero() {
+            smol::Timer::after(wait).await;
+        }
+    }
+}
+
+/// The global upload and download buckets, shared by every relay, the VPN and UDP associations.
+#[derive(Default)]
+pub struct Shaper {
+    upload: TokenBucket,
+    download: TokenBucket,
+}
+
+impl Shaper {
+    /// Waits until `n` bytes may go through the tunnel in `direction` under the global limits.
+    pub async fn pass(&self, limits: &BandwidthLimits, direction: Direction, n: usize) {
+        match direction {
+            Direction::Up => self.upload.take(n, limits.upload).await,
+            Direction::Down => self.download.take(n, limits.download).await,
+        }
+    }
+}
+
+/// Copies like [`geph4_aioutils::copy_with_stats`], but holds back each chunk until it fits under both the global limits and the per-connection limit. The limits are looked up for every chunk, so changing them applies to connections that are already open.
+pub async fn copy_shaped(
+    ctx: &ConnectContext,
+    reader: impl AsyncRead + Unpin,
+    writer: impl AsyncWrite + Unpin,
+    direction: Direction,
+    on_write: impl FnMut(usize),
+) -> std::io::Result<()> {
+    copy_limited(
+        &ctx.shaper,
+        || ctx.bandwidth_limits(),
+        reader,
+        writer,
+        direction,
+        on_write,
+    )
+    .await
+}
+
+/// The body of [`copy_shaped`], with the global buckets and the limits in effect given separately.
+async fn copy_limited(
+    shaper: &Shaper,
+    limits: impl Fn() -> BandwidthLimits,
+    reader: impl AsyncRead + Unpin,
+    writer: impl AsyncWrite + Unpin,
+    direction: Direction,
+    mut on_write: impl FnMut(usize),
+) -> std::io::Result<()> {
+    let own = TokenBucket::default();
+    let own = &own;
+    geph4_aioutils::copy_with_stats_async(reader, writer, |n| {
+        on_write(n);
+        let limits = limits();
+        async move {
+            shaper.pass(&limits, direction, n).await;
+            own.take(n, limits.per_connection).await;
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use super::*;
+
+    /// How long taking `n` bytes at `limit` KiB/s from `bucket` waits.
+    fn time_take(bucket: &TokenBucket, n: usize, limit: Option<u32>) -> Duration {
+        let start = Instant::now();
+        smol::block_on(bucket.take(n, limit));
+        start.elapsed()
+    }
+
+    /// The tokens that `bucket` had left as of the last take. Whoever took last waited only if this is negative.
+    fn tokens(bucket: &TokenBucket) -> f64 {
+        bucket
+            .state
+            .lock()
+            .expect("the bucket was never taken from")
+            .0
+    }
+
+    #[test]
+    fn unlimited_never_waits() {
+        let bucket = TokenBucket::default();
+        let start = Instant::now();
+        for _ in 0..10 {
+            smol::block_on(bucket.take(1 << 30, None));
+        }
+        // a gigabyte at any limit would take far longer
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(bucket.state.lock().is_none());
+    }
+
+    #[test]
+    fn a_second_passes_at_once() {
+        let bucket = TokenBucket::default();
+        smol::block_on(bucket.take(1024, Some(1)));
+        assert!(tokens(&bucket) >= 0.0, "{}", tokens(&bucket));
+    }
+
+    #[test]
+    fn debt_is_waited_out_by_later_takers() {
+        let bucket = TokenBucket::default();
+        // a quarter second over what the bucket holds
+        let first = time_take(&bucket, 1024 + 256, Some(1));
+        assert!(first >= Duration::from_millis(240), "{:?}", first);
+        assert!(
+            (-256.5..=-256.0).contains(&tokens(&bucket)),
+            "{}",
+            tokens(&bucket)
+        );
+        // the debt is paid off, so only the new byte is owed
+        smol::block_on(bucket.take(1, Some(1)));
+        assert!(tokens(&bucket) > -2.0, "{}", tokens(&bucket));
+
+        // a small chunk taken while the debt is still owed waits it out too
+        *bucket.state.lock() = Some((-256.0, Instant::now()));
+        let small = time_take(&bucket, 1, Some(1));
+        assert!(small >= Duration::from_millis(240), "{:?}", small);
+    }
+
+    #[test]
+    fn idle_time_refills_up_to_a_second() {
+        let bucket = TokenBucket::default();
+        // empty the bucket, then let it refill for longer than it can hold
+        *bucket.state.lock() = Some((0.0, Instant::now() - Duration::from_secs(5)));
+        let wait = time_take(&bucket, 1024 + 256, Some(1));
+        assert!(wait >= Duration::from_millis(240), "{:?}", wait);
+        // five seconds of refilling were capped at one
+        assert!(
+            (-256.5..=-256.0).contains(&tokens(&bucket)),
+            "{}",
+            tokens(&bucket)
+        );
+    }
+
+    /// How long copying `n` bytes through [`copy_limited`] takes, with the given global buckets and limits.
+    fn time_copy(
+        shaper: &Shaper,
+        limits: BandwidthLimits,
+        direction: Direction,
+        n: usize,
+    ) -> Duration {
+        let start = Instant::now();
+        let mut copied = 0;
+        let mut sink = vec![];
+        smol::block_on(copy_limited(
+            shaper,
+            || limits,
+            futures_util::io::Cursor::new(vec![0u8; n]),
+            futures_util::io::Cursor::new(&mut sink),
+            direction,
+            |n| copied += n,
+        ))
+        .unwrap();
+        assert_eq!((copied, sink.len()), (n, n));
+        start.elapsed()
+    }
+
+    #[test]
+    fn per_connection_limit_applies_to_each_connection_alone() {
+        let shaper = Shaper::default();
+        let limits = BandwidthLimits {
+            per_connection: Some(1),
+            ..Default::default()
+        };
+        // half a second over what one connection's bucket holds
+        let wait = time_copy(&shaper, limits, Direction::Up, 1024 + 512);
+        assert!(wait >= Duration::from_millis(450), "{:?}", wait);
+        // each other connection starts with a bucket of its own, where sharing one would make each of these wait a second
+        let start = Instant::now();
+        for _ in 0..2 {
+            time_copy(&shaper, limits, Direction::Down, 1024);
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(1),
+            "{:?}",
+            start.elapsed()
+        );
+        assert!(shaper.upload.state.lock().is_none());
+        assert!(shaper.download.state.lock().is_none());
+    }
+
+    #[test]
+    fn global_limits_are_shared_per_direction() {
+        let shaper = Shaper::default();
+        let limits = BandwidthLimits {
+            upload: Some(1),
+            ..Default::default()
+        };
+        time_copy(&shaper, limits, Direction::Up, 768);
+        assert!(tokens(&shaper.upload) >= 0.0, "{}", tokens(&shaper.upload));
+        // the second connection pays for the first one's share of the bucket
+        let wait = time_copy(&shaper, limits, Direction::Up, 768);
+        assert!(wait >= Duration::from_millis(450), "{:?}", wait);
+        // downloads are not held back by the upload limit
+        time_copy(&shaper, limits, Direction::Down, 1 << 20);
+        assert!(shaper.download.state.lock().is_none());
+    }
+
+    #[test]
+    fn limits_round_trip_through_options() {
+        let mut opt = ConnectOpt::from_iter_safe(["geph4-client"]).unwrap();
+        let limits = BandwidthLimits {
+            upload: Some(100),
+            download: None,
+            per_connection: Some(5),
+        };
+        limits.apply(&mut opt);
+        assert_eq!(BandwidthLimits::of(&opt), limits);
+    }
+}
@@ -0,0 +1,150 @@
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use socksv5::v5::SocksV5Host;
+
+use super::{
+    socks5::{dest_addr, host_and_ip, open_upstream, relay},
+    ConnectContext,
+};
+
+/// The reply code granting a SOCKS4 request.
+const GRANTED: u8 = 0x5a;
+/// The reply code rejecting a SOCKS4 request.
+const REJECTED: u8 = 0x5b;
+
+/// Handles a SOCKS4 or SOCKS4a client. Only CONNECT is supported. SOCKS4 has no way to send a password, so these clients are turned away while `--proxy-credentials` is in use.
+pub async fn handle_socks4(
+    ctx: ConnectContext,
+    client: smol::net::TcpStream,
+    exclude_prc: bool,
+) -> anyhow::Result<()> {
+    client.set_nodelay(true)?;
+    let Request {
+        command,
+        host,
+        port,
+    } = read_request(&mut client.clone()).await?;
+
+    if command != 1 {
+        reply(&client, REJECTED).await?;
+        anyhow::bail!("SOCKS4 command {} is not supported", command);
+    }
+    if ctx.users.required() {
+        reply(&client, REJECTED).await?;
+        anyhow::bail!("SOCKS4 clients cannot log in, but logins are required");
+    }
+    let (host, ip) = host_and_ip(&host);
+    let upstream = match open_upstream(&ctx, &host, ip, port, exclude_prc).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            reply(&client, REJECTED).await?;
+            return Err(err);
+        }
+    };
+    reply(&client, GRANTED).await?;
+    let client_addr = client.peer_addr()?;
+    relay(
+        &ctx,
+        client,
+        client_addr,
+        dest_addr(&host, ip, port),
+        upstream,
+        None,
+    )
+    .await
+}
+
+/// A SOCKS4 or SOCKS4a request.
+struct Request {
+    command: u8,
+    host: SocksV5Host,
+    port: u16,
+}
+
+/// Reads a SOCKS4 request, along with the domain that SOCKS4a sends after it.
+async fn read_request(reader: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Request> {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header).await?;
+    anyhow::ensure!(header[0] == 4, "not a SOCKS4 request");
+    let command = header[1];
+    let port = u16::from_be_bytes([header[2], header[3]]);
+    let dst = [header[4], header[5], header[6], header[7]];
+    // the user ID, which we have no use for
+    read_nul_terminated(reader).await?;
+    // SOCKS4a sends the domain after the user ID, and a destination of 0.0.0.x (x != 0) to say so
+    let host = if dst[..3] == [0, 0, 0] && dst[3] != 0 {
+        SocksV5Host::Domain(read_nul_terminated(reader).await?)
+    } else {
+        SocksV5Host::Ipv4(dst)
+    };
+    Ok(Request {
+        command,
+        host,
+        port,
+    })
+}
+
+async fn reply(client: &smol::net::TcpStream, code: u8) -> anyhow::Result<()> {
+    // the address fields only matter for BIND, which we do not support
+    client
+        .clone()
+        .write_all(&[0, code, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+/// Reads a NUL-terminated field of at most 255 bytes.
+async fn read_nul_terminated(reader: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Vec<u8>> {
+    let mut field = vec![];
+    loop {
+        let mut byte = [0u8];
+        reader.read_exact(&mut byte).await?;
+        if byte[0] == 0 {
+            return Ok(field);
+        }
+        anyhow::ensure!(field.len() < 255, "overlong field in SOCKS4 request");
+        field.push(byte[0]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(request: &[u8]) -> anyhow::Result<Request> {
+        smol::block_on(read_request(&mut &request[..]))
+    }
+
+    #[test]
+    fn socks4_request() {
+        let request = parse(b"\x04\x01\x00\x50\xc0\x00\x02\x01user\x00").unwrap();
+        assert_eq!(request.command, 1);
+        assert_eq!(request.port, 80);
+        assert!(matches!(request.host, SocksV5Host::Ipv4([192, 0, 2, 1])));
+    }
+
+    #[test]
+    fn socks4a_request() {
+        let request = parse(b"\x04\x01\x01\xbb\x00\x00\x00\x01\x00example.com\x00").unwrap();
+        assert_eq!(request.port, 443);
+        assert!(matches!(request.host, SocksV5Host::Domain(dom) if dom == b"example.com"));
+        // 0.0.0.0 is a plain address, not a SOCKS4a marker
+        let request = parse(b"\x04\x02\x00\x50\x00\x00\x00\x00\x00").unwrap();
+        assert_eq!(request.command, 2);
+        assert!(matches!(request.host, SocksV5Host::Ipv4([0, 0, 0, 0])));
+    }
+
+    #[test]
+    fn bad_requests() {
+        // SOCKS5 greeting
+        assert!(parse(b"\x05\x01\x00\x00\x00\x00\x00\x00\x00").is_err());
+        // truncated header, and user ID without its NUL
+        assert!(parse(b"\x04\x01\x00\x50").is_err());
+        assert!(parse(b"\x04\x01\x00\x50\xc0\x00\x02\x01user").is_err());
+        // SOCKS4a without its domain
+        assert!(parse(b"\x04\x01\x00\x50\x00\x00\x00\x01\x00").is_err());
+        let mut overlong = b"\x04\x01\x00\x50\xc0\x00\x02\x01".to_vec();
+        overlong.extend_from_slice(&[b'u'; 300]);
+        overlong.push(0);
+        assert!(parse(&overlong).is_err());
+    }
+}
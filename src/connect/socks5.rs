@@ -1,122 +1,313 @@
-use std::{sync::atomic::Ordering, time::Duration};
+use std::{
+    io,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 
 use anyhow::Context;
-use futures_util::{AsyncReadExt, TryFutureExt};
-use psl::Psl;
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, FutureExt, TryFutureExt};
+use sillad::Pipe;
 use smol_timeout::TimeoutExt;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
-use crate::{
-    china,
-    connect::stats::{STATS_RECV_BYTES, STATS_SEND_BYTES},
+use crate::connect::{
+    acl::ListenerKind,
+    connections::Route,
+    proxy_users::UserCounter,
+    routing::{self, Action, Rejected},
+    shaping::{copy_shaped, Direction},
+    stats::{STATS_RECV_BYTES, STATS_SEND_BYTES},
+    tunnel::{OpenFailure, Unresolvable},
 };
 
 use super::ConnectContext;
+use socksv5::v5::*;
+
+mod udp;
 
 /// Handles a socks5 client from localhost
-async fn handle_socks5(
+pub async fn handle_socks5(
     ctx: ConnectContext,
     s5client: smol::net::TcpStream,
     exclude_prc: bool,
 ) -> anyhow::Result<()> {
     s5client.set_nodelay(true)?;
-    use socksv5::v5::*;
-    let _handshake = read_handshake(s5client.clone()).await?;
-    write_auth_method(s5client.clone(), SocksV5AuthMethod::Noauth).await?;
+    let handshake = read_handshake(s5client.clone()).await?;
+    let user = authenticate(&ctx, &s5client, &handshake.methods).await?;
     let request = read_request(s5client.clone()).await?;
+    match request.command {
+        SocksV5Command::Connect => {}
+        SocksV5Command::UdpAssociate => {
+            return udp::handle_udp_associate(ctx, s5client, exclude_prc, user).await;
+        }
+        SocksV5Command::Bind => {
+            write_request_status(
+                s5client,
+                SocksV5RequestStatus::CommandNotSupported,
+                request.host,
+                request.port,
+            )
+            .await?;
+            anyhow::bail!("BIND is not supported")
+        }
+    }
     let port = request.port;
-    let v4addr: Option<Ipv4Addr>;
-    let addr: String = match &request.host {
+    let (host, ip) = host_and_ip(&request.host);
+    let upstream = match open_upstream(&ctx, &host, ip, port, exclude_prc).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            // tell the client right away, and why, so that it can decide whether to retry
+            write_request_status(s5client, failure_status(&err), request.host, port).await?;
+            return Err(err);
+        }
+    };
+    write_request_status(
+        s5client.clone(),
+        SocksV5RequestStatus::Success,
+        request.host,
+        port,
+    )
+    .await?;
+    let client_addr = s5client.peer_addr()?;
+    relay(
+        &ctx,
+        s5client,
+        client_addr,
+        dest_addr(&host, ip, port),
+        upstream,
+        user,
+    )
+    .await
+}
+
+/// The reply telling a SOCKS5 client why its connection could not be opened. Clients only ever see network unreachable when the tunnel is down.
+fn failure_status(err: &anyhow::Error) -> SocksV5RequestStatus {
+    match OpenFailure::of(err) {
+        OpenFailure::TunnelDown => SocksV5RequestStatus::NetworkUnreachable,
+        OpenFailure::TimedOut => SocksV5RequestStatus::TtlExpired,
+        OpenFailure::Unreachable => SocksV5RequestStatus::HostUnreachable,
+        OpenFailure::Refused => SocksV5RequestStatus::ConnectionRefused,
+        OpenFailure::Rejected => SocksV5RequestStatus::ConnectionNotAllowed,
+        OpenFailure::Other => SocksV5RequestStatus::ServerFailure,
+    }
+}
+
+/// Splits a SOCKS destination into a printable host and, if it is an IP literal, its address.
+pub fn host_and_ip(host: &SocksV5Host) -> (String, Option<IpAddr>) {
+    match host {
         SocksV5Host::Domain(dom) => {
-            v4addr = String::from_utf8_lossy(dom).parse().ok();
-            format!("{}:{}", String::from_utf8_lossy(dom), request.port)
+            let dom = String::from_utf8_lossy(dom).to_string();
+            let ip = dom
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse()
+                .ok();
+            (dom, ip)
         }
-        SocksV5Host::Ipv4(v4) => {
-            let v4addr_inner = Ipv4Addr::new(v4[0], v4[1], v4[2], v4[3]);
-            SocketAddr::V4(SocketAddrV4::new(
-                {
-                    v4addr = Some(v4addr_inner);
-                    v4addr.unwrap()
-                },
-                request.port,
-            ))
-            .to_string()
+        SocksV5Host::Ipv4(v4) => (Ipv4Addr::from(*v4).to_string(), Some(IpAddr::from(*v4))),
+        SocksV5Host::Ipv6(v6) => (Ipv6Addr::from(*v6).to_string(), Some(IpAddr::from(*v6))),
+    }
+}
+
+/// The `host:port` that a proxied connection is for, with IPv6 literals in brackets.
+pub fn dest_addr(host: &str, ip: Option<IpAddr>, port: u16) -> String {
+    match ip {
+        Some(ip) => SocketAddr::new(ip, port).to_string(),
+        None => format!("{}:{}", host, port),
+    }
+}
+
+/// Where a proxied connection goes: straight to the destination, or through the tunnel.
+pub enum UpstreamConn {
+    Direct(smol::net::TcpStream),
+    Tunneled(Box<dyn Pipe>),
+}
+
+/// An opened upstream connection, along with the routing rule that sent it that way.
+pub struct Upstream {
+    pub conn: UpstreamConn,
+    pub rule: String,
+}
+
+/// Opens the connection that a proxied request to `host:port` goes out on, going wherever the routing rules say.
+pub async fn open_upstream(
+    ctx: &ConnectContext,
+    host: &str,
+    ip: Option<IpAddr>,
+    port: u16,
+    exclude_prc: bool,
+) -> anyhow::Result<Upstream> {
+    let addr = dest_addr(host, ip, port);
+    let decision = ctx.router.route(host, ip, exclude_prc);
+    log::debug!("{} {} by rule {}", decision.action, addr, decision.rule);
+    let conn = match decision.action {
+        Action::Reject => return Err(Rejected(decision.rule).into()),
+        Action::Direct => UpstreamConn::Direct(connect_direct(ctx, host, ip, &addr).await?),
+        Action::Proxy => {
+            let grace = Duration::from_secs(ctx.opt().tunnel_down_grace);
+            let conn = ctx
+                .tunnel
+                .connect_stream_within(&addr, grace)
+                .timeout(Duration::from_secs(120))
+                .await
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::TimedOut, "open connection timeout")
+                })??;
+            UpstreamConn::Tunneled(conn)
         }
-        _ => anyhow::bail!("not supported"),
     };
+    Ok(Upstream {
+        conn,
+        rule: decision.rule,
+    })
+}
 
-    let is_private = if let Some(v4addr) = v4addr {
-        v4addr.is_private() || v4addr.is_loopback()
-    } else {
-        !psl::List
-            .suffix(addr.split(':').next().unwrap().as_bytes())
-            .map(|suf| suf.typ().is_some())
-            .unwrap_or_default()
+/// Copies data both ways between a proxy client and its upstream until either side is done, or until it is closed over the stats RPC. The data counts toward the tunnel stats, the client's user, and the connection's entry in the connection table.
+pub async fn relay(
+    ctx: &ConnectContext,
+    client: impl AsyncRead + AsyncWrite + Send + Unpin,
+    client_addr: SocketAddr,
+    destination: String,
+    upstream: Upstream,
+    user: Option<Arc<UserCounter>>,
+) -> anyhow::Result<()> {
+    let route = match upstream.conn {
+        UpstreamConn::Direct(_) => Route::Direct,
+        UpstreamConn::Tunneled(_) => Route::Tunnel,
     };
+    let tracked = ctx
+        .connections
+        .register(client_addr, destination, route, upstream.rule);
+    let count_sent = |n: usize| {
+        tracked.add_sent(n);
+        if let Some(user) = &user {
+            user.add_sent(n)
+        }
+    };
+    let count_recv = |n: usize| {
+        tracked.add_recv(n);
+        if let Some(user) = &user {
+            user.add_recv(n)
+        }
+    };
+    let (client_read, client_write) = client.split();
+    let copy = async {
+        match upstream.conn {
+            UpstreamConn::Direct(conn) => {
+                smol::future::race(
+                    geph4_aioutils::copy_with_stats(conn.clone(), client_write, count_recv),
+                    geph4_aioutils::copy_with_stats(client_read, conn, count_sent),
+                )
+                .await
+            }
+            UpstreamConn::Tunneled(conn) => {
+                let (conn_read, conn_write) = conn.split();
+                smol::future::race(
+                    copy_shaped(ctx, conn_read, client_write, Direction::Down, |n| {
+                        STATS_RECV_BYTES.fetch_add(n as u64, Ordering::Relaxed);
+                        count_recv(n);
+                    }),
+                    copy_shaped(ctx, client_read, conn_write, Direction::Up, |n| {
+                        STATS_SEND_BYTES.fetch_add(n as u64, Ordering::Relaxed);
+                        count_sent(n);
+                    }),
+                )
+                .await
+            }
+        }
+    };
+    let closed = async {
+        tracked.closed().await;
+        log::debug!("closing a relay as asked over the stats RPC");
+        Ok(())
+    };
+    smol::future::race(copy, closed).await?;
+    Ok(())
+}
 
-    // true if the connection should not go through geph
-    let must_direct = is_private
-        || (exclude_prc
-            && (china::is_chinese_host(addr.split(':').next().unwrap())
-                || v4addr.map(china::is_chinese_ip).unwrap_or(false)));
-    if must_direct {
-        log::debug!("bypassing {}", addr);
-        let conn = smol::net::TcpStream::connect(&addr).await?;
-        write_request_status(
-            s5client.clone(),
-            SocksV5RequestStatus::Success,
-            request.host,
-            port,
-        )
-        .await?;
-        smol::future::race(
-            geph4_aioutils::copy_with_stats(conn.clone(), s5client.clone(), |_| ()),
-            geph4_aioutils::copy_with_stats(s5client.clone(), conn.clone(), |_| ()),
-        )
-        .await?;
-    } else {
-        let conn = ctx
-            .tunnel
-            .connect_stream(&addr)
-            .timeout(Duration::from_secs(120))
-            .await
-            .context("open connection timeout")??;
-        write_request_status(
-            s5client.clone(),
-            SocksV5RequestStatus::Success,
-            request.host,
-            port,
-        )
-        .await?;
-        let (conn_read, conn_write) = conn.split();
-        smol::future::race(
-            geph4_aioutils::copy_with_stats(conn_read, s5client.clone(), |n| {
-                STATS_RECV_BYTES.fetch_add(n as u64, Ordering::Relaxed);
-            }),
-            geph4_aioutils::copy_with_stats(s5client, conn_write, |n| {
-                STATS_SEND_BYTES.fetch_add(n as u64, Ordering::Relaxed);
-            }),
-        )
-        .await?;
+/// Connects to a destination that the routing rules send direct, through `--direct-upstream` unless it is on the local network.
+async fn connect_direct(
+    ctx: &ConnectContext,
+    host: &str,
+    ip: Option<IpAddr>,
+    addr: &str,
+) -> anyhow::Result<smol::net::TcpStream> {
+    match &ctx.opt().direct_upstream {
+        Some(proxy) if !routing::is_private(host, ip) => proxy.connect(addr).await,
+        _ => {
+            // resolved separately, so that a name that does not resolve is told apart from other failures
+            let addrs = smol::net::resolve(addr)
+                .await
+                .ok()
+                .filter(|addrs| !addrs.is_empty())
+                .ok_or_else(|| Unresolvable(addr.to_string()))?;
+            Ok(smol::net::TcpStream::connect(&addrs[..]).await?)
+        }
     }
-    Ok(())
 }
 
-pub async fn socks5_loop(ctx: ConnectContext) -> anyhow::Result<()> {
-    let socks5_listener = smol::net::TcpListener::bind(ctx.opt.socks5_listen)
+/// Negotiates authentication with a SOCKS5 client. When logins are required, this checks one per RFC 1929 and returns the user's counters.
+async fn authenticate(
+    ctx: &ConnectContext,
+    s5client: &smol::net::TcpStream,
+    methods: &[SocksV5AuthMethod],
+) -> anyhow::Result<Option<Arc<UserCounter>>> {
+    if !ctx.users.required() {
+        write_auth_method(s5client.clone(), SocksV5AuthMethod::Noauth).await?;
+        return Ok(None);
+    }
+    if !methods.contains(&SocksV5AuthMethod::UsernamePassword) {
+        write_auth_method(s5client.clone(), SocksV5AuthMethod::NoAcceptableMethod).await?;
+        anyhow::bail!("client does not offer username/password authentication");
+    }
+    write_auth_method(s5client.clone(), SocksV5AuthMethod::UsernamePassword).await?;
+    let mut s5client = s5client.clone();
+    let mut header = [0u8; 2];
+    s5client.read_exact(&mut header).await?;
+    anyhow::ensure!(
+        header[0] == 1,
+        "unknown username/password auth version {}",
+        header[0]
+    );
+    let mut username = vec![0u8; header[1] as usize];
+    s5client.read_exact(&mut username).await?;
+    let mut password_len = [0u8];
+    s5client.read_exact(&mut password_len).await?;
+    let mut password = vec![0u8; password_len[0] as usize];
+    s5client.read_exact(&mut password).await?;
+    let username = String::from_utf8_lossy(&username);
+    let user = ctx
+        .users
+        .check(&username, &String::from_utf8_lossy(&password));
+    s5client
+        .write_all(&[1, if user.is_some() { 0 } else { 1 }])
+        .await?;
+    let user = user.with_context(|| format!("wrong SOCKS5 login for user {:?}", username))?;
+    Ok(Some(user))
+}
+
+pub async fn socks5_loop(ctx: ConnectContext, addr: SocketAddr) -> anyhow::Result<()> {
+    let socks5_listener = smol::net::TcpListener::bind(addr)
         .await
         .context("cannot bind socks5")?;
     log::debug!("socks5 started");
     loop {
-        let (s5client, _) = socks5_listener
+        let (s5client, client_addr) = socks5_listener
             .accept()
             .await
             .context("cannot accept socks5")?;
+        if !ctx.permit(ListenerKind::Socks5, client_addr) {
+            continue;
+        }
+        let Some(admitted) = ctx.admit(client_addr) else {
+            continue;
+        };
 
-        smolscale::spawn(
-            handle_socks5(ctx.clone(), s5client, ctx.opt.exclude_prc)
-                .map_err(|e| log::debug!("local socks5 handler died with: {:?}", e)),
+        let exclude_prc = ctx.opt().exclude_prc;
+        ctx.relays.spawn(
+            handle_socks5(ctx.clone(), s5client, exclude_prc)
+                .map_err(|e| log::debug!("local socks5 handler died with: {:?}", e))
+                .map(move |_| drop(admitted)),
         )
-        .detach()
     }
 }
@@ -0,0 +1,565 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use clone_macro::clone;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use parking_lot::Mutex;
+use smol::future::FutureExt;
+use smol_timeout::TimeoutExt;
+use smolscale::reaper::TaskReaper;
+use socksv5::v5::*;
+
+use crate::connect::{
+    proxy_users::UserCounter,
+    routing::Action,
+    shaping::Direction,
+    stats::{STATS_RECV_BYTES, STATS_SEND_BYTES},
+    tunnel::TunnelUdpSocket,
+    ConnectContext,
+};
+
+/// How long an association may go without a datagram in either direction before it is torn down.
+const ASSOCIATION_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Datagrams kept for a name while it is being resolved. Any more are dropped.
+const MAX_QUEUED_DATAGRAMS: usize = 16;
+
+/// Where a name that datagrams are sent to stands.
+enum Resolution {
+    /// Being looked up, with the destination ports and payloads waiting for it.
+    Pending(Vec<(u16, Vec<u8>)>),
+    Resolved(IpAddr),
+}
+
+/// Handles a UDP ASSOCIATE request. Datagrams are relayed through the tunnel's packet path, except for those that the routing rules send direct or reject, and the association lasts until the client closes the control connection or it goes idle.
+pub async fn handle_udp_associate(
+    ctx: ConnectContext,
+    s5client: smol::net::TcpStream,
+    exclude_prc: bool,
+    user: Option<Arc<UserCounter>>,
+) -> anyhow::Result<()> {
+    let client_ip = s5client.peer_addr()?.ip();
+    // clients expect the relay on the same interface they reached us on
+    let relay = smol::net::UdpSocket::bind(SocketAddr::new(s5client.local_addr()?.ip(), 0))
+        .await
+        .context("cannot bind UDP relay")?;
+    let direct = smol::net::UdpSocket::bind("0.0.0.0:0")
+        .await
+        .context("cannot bind direct UDP socket")?;
+    // not every machine has IPv6, so going direct to IPv6 destinations is best-effort
+    let direct6 = smol::net::UdpSocket::bind("[::]:0").await.ok();
+    let direct_socket = |ip: IpAddr| match (ip, &direct6) {
+        (IpAddr::V6(_), Some(direct6)) => direct6,
+        _ => &direct,
+    };
+    let tunneled = ctx.tunnel.bind_udp()?;
+    let bound = relay.local_addr()?;
+    write_request_status(
+        s5client.clone(),
+        SocksV5RequestStatus::Success,
+        host_of(bound.ip()),
+        bound.port(),
+    )
+    .await?;
+    log::debug!("UDP association for {} relaying on {}", client_ip, bound);
+
+    // the first datagram from the client's IP decides which of its ports we answer to
+    let client_addr: Mutex<Option<SocketAddr>> = Mutex::new(None);
+    let last_active = Mutex::new(Instant::now());
+    let touch = || *last_active.lock() = Instant::now();
+
+    // names are resolved in the background, so that a slow lookup never holds up other destinations. Names sent direct are looked up by the system, and the rest through the tunnel.
+    let names: Mutex<HashMap<(Action, String), Resolution>> = Mutex::new(HashMap::new());
+    let lookups = TaskReaper::new();
+    let (send_resolved, recv_resolved) = smol::channel::unbounded();
+
+    let up_loop = async {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (n, from) = relay.recv_from(&mut buf).await?;
+            if from.ip() != client_ip || *client_addr.lock().get_or_insert(from) != from {
+                continue;
+            }
+            touch();
+            let Some((host, port, payload)) = parse_datagram(&buf[..n]) else {
+                log::debug!("dropping malformed or fragmented SOCKS5 datagram");
+                continue;
+            };
+            let (name, ip) = super::host_and_ip(&host);
+            let decision = ctx.router.route(&name, ip, exclude_prc);
+            if decision.action == Action::Reject {
+                log::trace!(
+                    "dropping datagram to {}:{} by rule {}",
+                    name,
+                    port,
+                    decision.rule
+                );
+                continue;
+            }
+            let action = decision.action;
+            let ip = match ip {
+                Some(ip) => ip,
+                None => {
+                    let key = (action, name);
+                    let mut names = names.lock();
+                    match names.get_mut(&key) {
+                        Some(Resolution::Resolved(ip)) => *ip,
+                        Some(Resolution::Pending(queued)) => {
+                            if queued.len() < MAX_QUEUED_DATAGRAMS {
+                                queued.push((port, payload.to_vec()));
+                            }
+                            continue;
+                        }
+                        None => {
+                            names.insert(
+                                key.clone(),
+                                Resolution::Pending(vec![(port, payload.to_vec())]),
+                            );
+                            let ipv6 = direct6.is_some();
+                            lookups.attach(smolscale::spawn(clone!(
+                                [ctx, send_resolved],
+                                async move {
+                                    let result = match key.0 {
+                                        Action::Direct => resolve_direct(&key.1, ipv6).await,
+                                        _ => resolve_through_tunnel(&ctx, &key.1).await,
+                                    };
+                                    let _ = send_resolved.send((key, result)).await;
+                                }
+                            )));
+                            continue;
+                        }
+                    }
+                }
+            };
+            if action == Action::Direct {
+                log::trace!("bypassing {}:{}", ip, port);
+                send_direct(
+                    direct_socket(ip),
+                    user.as_deref(),
+                    payload,
+                    SocketAddr::new(ip, port),
+                )
+                .await;
+                continue;
+            }
+            send_tunneled(
+                &ctx,
+                &tunneled,
+                user.as_deref(),
+                payload,
+                SocketAddr::new(ip, port),
+            )
+            .await;
+        }
+    };
+    let resolved_loop = async {
+        loop {
+            let (key, result): ((Action, String), _) = recv_resolved.recv().await?;
+            let ip = match result {
+                Ok(ip) => ip,
+                Err(err) => {
+                    log::debug!("cannot resolve {}: {:?}", key.1, err);
+                    // the next datagram for the name tries again
+                    names.lock().remove(&key);
+                    continue;
+                }
+            };
+            let action = key.0;
+            let queued = match names.lock().insert(key, Resolution::Resolved(ip)) {
+                Some(Resolution::Pending(queued)) => queued,
+                _ => vec![],
+            };
+            for (port, payload) in queued {
+                let dest = SocketAddr::new(ip, port);
+                if action == Action::Direct {
+                    send_direct(direct_socket(ip), user.as_deref(), &payload, dest).await;
+                } else {
+                    send_tunneled(&ctx, &tunneled, user.as_deref(), &payload, dest).await;
+                }
+            }
+        }
+    };
+    let tunneled_dn_loop = async {
+        loop {
+            let (from, payload) = tunneled.recv_from().await?;
+            touch();
+            ctx.shape(Direction::Down, payload.len()).await;
+            STATS_RECV_BYTES.fetch_add(payload.len() as u64, Ordering::Relaxed);
+            if let Some(user) = &user {
+                user.add_recv(payload.len());
+            }
+            let client = *client_addr.lock();
+            if let Some(client) = client {
+                relay
+                    .send_to(&encode_datagram(from, &payload), client)
+                    .await?;
+            }
+        }
+    };
+    let (user, client_addr, relay, touch) = (&user, &client_addr, &relay, &touch);
+    let direct_dn_loop = |direct: &smol::net::UdpSocket| {
+        let direct = direct.clone();
+        async move {
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let (n, from) = direct.recv_from(&mut buf).await?;
+                touch();
+                if let Some(user) = user {
+                    user.add_recv(n);
+                }
+                let client = *client_addr.lock();
+                if let Some(client) = client {
+                    relay
+                        .send_to(&encode_datagram(from, &buf[..n]), client)
+                        .await?;
+                }
+            }
+        }
+    };
+    let control_loop = async {
+        // nothing more is ever sent on the control connection, so any read returning means it is gone
+        let mut buf = [0u8; 512];
+        while s5client.clone().read(&mut buf).await? > 0 {}
+        log::debug!("UDP association for {} closed by the client", client_ip);
+        anyhow::Ok(())
+    };
+    let idle_loop = async {
+        loop {
+            smol::Timer::after(Duration::from_secs(5)).await;
+            if last_active.lock().elapsed() > ASSOCIATION_IDLE_TIMEOUT {
+                log::debug!("UDP association for {} timed out", client_ip);
+                return anyhow::Ok(());
+            }
+        }
+    };
+    let direct6_dn_loop = async {
+        match &direct6 {
+            Some(direct6) => direct_dn_loop(direct6).await,
+            None => smol::future::pending().await,
+        }
+    };
+    up_loop
+        .race(resolved_loop)
+        .race(tunneled_dn_loop)
+        .race(direct_dn_loop(&direct))
+        .race(direct6_dn_loop)
+        .race(control_loop)
+        .race(idle_loop)
+        .await
+}
+
+/// Sends a datagram through the tunnel, accounting for it. A datagram that cannot be sent is only logged, since that should not end the whole association.
+async fn send_tunneled(
+    ctx: &ConnectContext,
+    tunneled: &TunnelUdpSocket,
+    user: Option<&UserCounter>,
+    payload: &[u8],
+    dest: SocketAddr,
+) {
+    ctx.shape(Direction::Up, payload.len()).await;
+    if let Err(err) = tunneled.send_to(payload, dest).await {
+        log::debug!("datagram to {} through the tunnel failed: {:?}", dest, err);
+        return;
+    }
+    STATS_SEND_BYTES.fetch_add(payload.len() as u64, Ordering::Relaxed);
+    if let Some(user) = user {
+        user.add_sent(payload.len());
+    }
+}
+
+/// Sends a datagram that the routing rules send direct, accounting for it. Like with the tunnel, a datagram that cannot be sent is only logged.
+async fn send_direct(
+    direct: &smol::net::UdpSocket,
+    user: Option<&UserCounter>,
+    payload: &[u8],
+    dest: SocketAddr,
+) {
+    match direct.send_to(payload, dest).await {
+        Ok(n) => {
+            if let Some(user) = user {
+                user.add_sent(n);
+            }
+        }
+        Err(err) => log::debug!("direct datagram to {} failed: {:?}", dest, err),
+    }
+}
+
+fn host_of(ip: IpAddr) -> SocksV5Host {
+    match ip {
+        IpAddr::V4(v4) => SocksV5Host::Ipv4(v4.octets()),
+        IpAddr::V6(v6) => SocksV5Host::Ipv6(v6.octets()),
+    }
+}
+
+/// Splits a SOCKS5 UDP datagram into its destination and payload. Fragments are not supported, so they come out as None just like malformed datagrams.
+fn parse_datagram(buf: &[u8]) -> Option<(SocksV5Host, u16, &[u8])> {
+    let (rsv_frag, rest) = buf.split_at_checked(3)?;
+    if rsv_frag[2] != 0 {
+        return None;
+    }
+    let (&atyp, rest) = rest.split_first()?;
+    let (host, rest) = match SocksV5AddressType::from_u8(atyp)? {
+        SocksV5AddressType::Ipv4 => {
+            let (addr, rest) = rest.split_at_checked(4)?;
+            (SocksV5Host::Ipv4(addr.try_into().ok()?), rest)
+        }
+        SocksV5AddressType::Ipv6 => {
+            let (addr, rest) = rest.split_at_checked(16)?;
+            (SocksV5Host::Ipv6(addr.try_into().ok()?), rest)
+        }
+        SocksV5AddressType::Domain => {
+            let (&len, rest) = rest.split_first()?;
+            let (dom, rest) = rest.split_at_checked(len as usize)?;
+            (SocksV5Host::Domain(dom.to_vec()), rest)
+        }
+    };
+    let (port, payload) = rest.split_at_checked(2)?;
+    Some((host, u16::from_be_bytes([port[0], port[1]]), payload))
+}
+
+/// Wraps a payload in a SOCKS5 UDP datagram header saying where it came from.
+fn encode_datagram(from: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut out = vec![0, 0, 0];
+    match from.ip() {
+        IpAddr::V4(v4) => {
+            out.push(SocksV5AddressType::Ipv4.to_u8());
+            out.extend_from_slice(&v4.octets());
+        }
+        IpAddr::V6(v6) => {
+            out.push(SocksV5AddressType::Ipv6.to_u8());
+            out.extend_from_slice(&v6.octets());
+        }
+    }
+    out.extend_from_slice(&from.port().to_be_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Looks up a name's address with DNS queries sent through the tunnel, so that the lookup does not leak. IPv4 addresses are preferred, and the IPv6 address is used if there is none.
+async fn resolve_through_tunnel(ctx: &ConnectContext, name: &str) -> anyhow::Result<IpAddr> {
+    let grace = Duration::from_secs(ctx.opt().tunnel_down_grace);
+    let timeout = Duration::from_secs(10);
+    let exchange = async {
+        let mut conn = ctx
+            .tunnel
+            .connect_stream_within("1.0.0.1:53", grace)
+            .await?;
+        for qtype in [RTYPE_A, RTYPE_AAAA] {
+            let query = dns_query(name, qtype)?;
+            conn.write_all(&(query.len() as u16).to_be_bytes()).await?;
+            conn.write_all(&query).await?;
+            conn.flush().await?;
+            let mut n_buf = [0u8; 2];
+            conn.read_exact(&mut n_buf).await?;
+            let mut response = vec![0u8; u16::from_be_bytes(n_buf) as usize];
+            conn.read_exact(&mut response).await?;
+            if let Some(ip) = first_address(&response, qtype) {
+                return Ok(ip);
+            }
+        }
+        anyhow::bail!("no A or AAAA record for {}", name)
+    };
+    exchange
+        .timeout(timeout)
+        .await
+        .context("DNS query timed out")?
+}
+
+/// Looks up a name that the routing rules send direct with the system resolver. IPv4 addresses are preferred, and IPv6 ones are only used if `ipv6` says that they can be reached.
+async fn resolve_direct(name: &str, ipv6: bool) -> anyhow::Result<IpAddr> {
+    let addrs = smol::net::resolve((name, 0)).await?;
+    pick_address(addrs.iter().map(|addr| addr.ip()), ipv6)
+        .with_context(|| format!("no usable address for {}", name))
+}
+
+/// Picks the first IPv4 address, or failing that the first IPv6 address if `ipv6` allows it.
+fn pick_address(addrs: impl Iterator<Item = IpAddr> + Clone, ipv6: bool) -> Option<IpAddr> {
+    addrs
+        .clone()
+        .find(IpAddr::is_ipv4)
+        .or_else(|| addrs.clone().find(IpAddr::is_ipv6).filter(|_| ipv6))
+}
+
+const RTYPE_A: u16 = 1;
+const RTYPE_AAAA: u16 = 28;
+
+/// Builds a recursive DNS query for a name's records of the given type.
+fn dns_query(name: &str, qtype: u16) -> anyhow::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&fastrand::u16(..).to_be_bytes());
+    // recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        anyhow::ensure!(
+            !label.is_empty() && label.len() < 64,
+            "invalid domain name {:?}",
+            name
+        );
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    // class IN
+    query.extend_from_slice(&[0, 1]);
+    Ok(query)
+}
+
+/// Finds the first A or AAAA record, as given by `rtype`, among the answers of a DNS response.
+fn first_address(response: &[u8], rtype: u16) -> Option<IpAddr> {
+    let count = |at: usize| {
+        Some(u16::from_be_bytes(
+            response.get(at..at + 2)?.try_into().ok()?,
+        ))
+    };
+    let questions = count(4)?;
+    let answers = count(6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(response, pos)? + 4;
+    }
+    for _ in 0..answers {
+        pos = skip_name(response, pos)?;
+        let rdlen = count(pos + 8)? as usize;
+        let rdata = response.get(pos + 10..pos + 10 + rdlen)?;
+        if count(pos)? == rtype {
+            if let Ok(octets) = <[u8; 4]>::try_from(rdata) {
+                return Some(Ipv4Addr::from(octets).into());
+            }
+            if let Ok(octets) = <[u8; 16]>::try_from(rdata) {
+                return Some(Ipv6Addr::from(octets).into());
+            }
+        }
+        pos += 10 + rdlen;
+    }
+    None
+}
+
+/// Returns the position just past a possibly-compressed name.
+fn skip_name(buf: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *buf.get(pos)?;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            return Some(pos + 2);
+        }
+        pos += 1 + len as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn datagrams_round_trip() {
+        let from: SocketAddr = "[2001:db8::1]:5353".parse().unwrap();
+        let encoded = encode_datagram(from, b"hello");
+        let (host, port, payload) = parse_datagram(&encoded).unwrap();
+        assert_eq!(super::super::host_and_ip(&host).1, Some(from.ip()));
+        assert_eq!(port, 5353);
+        assert_eq!(payload, b"hello");
+
+        let encoded = encode_datagram("192.0.2.1:53".parse().unwrap(), b"");
+        let (host, port, payload) = parse_datagram(&encoded).unwrap();
+        assert!(matches!(host, SocksV5Host::Ipv4([192, 0, 2, 1])));
+        assert_eq!(port, 53);
+        assert!(payload.is_empty());
+    }
+
+    #[test]
+    fn parse_domain_datagram() {
+        let mut buf = vec![0, 0, 0, 3, 11];
+        buf.extend_from_slice(b"example.com");
+        buf.extend_from_slice(&[0x01, 0xbb]);
+        buf.extend_from_slice(b"payload");
+        let (host, port, payload) = parse_datagram(&buf).unwrap();
+        assert!(matches!(host, SocksV5Host::Domain(dom) if dom == b"example.com"));
+        assert_eq!(port, 443);
+        assert_eq!(payload, b"payload");
+    }
+
+    #[test]
+    fn reject_bad_datagrams() {
+        // fragmented
+        assert!(parse_datagram(&[0, 0, 1, 1, 192, 0, 2, 1, 0, 53]).is_none());
+        // unknown address type
+        assert!(parse_datagram(&[0, 0, 0, 9, 192, 0, 2, 1, 0, 53]).is_none());
+        // truncated address, domain and port
+        assert!(parse_datagram(&[0, 0, 0, 1, 192, 0]).is_none());
+        assert!(parse_datagram(&[0, 0, 0, 3, 20, b'a']).is_none());
+        assert!(parse_datagram(&[0, 0, 0, 1, 192, 0, 2, 1, 0]).is_none());
+        assert!(parse_datagram(&[0, 0]).is_none());
+    }
+
+    #[test]
+    fn direct_addresses_prefer_ipv4() {
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(pick_address([v6, v4].into_iter(), true), Some(v4));
+        assert_eq!(pick_address([v6].into_iter(), true), Some(v6));
+        assert_eq!(pick_address([v6].into_iter(), false), None);
+        assert_eq!(pick_address([].into_iter(), true), None);
+    }
+
+    #[test]
+    fn skip_names() {
+        // "a.bc" followed by the root label
+        let buf = [1, b'a', 2, b'b', b'c', 0, 0xff];
+        assert_eq!(skip_name(&buf, 0), Some(6));
+        // a compression pointer ends the name
+        let buf = [1, b'a', 0xc0, 12, 0xff];
+        assert_eq!(skip_name(&buf, 0), Some(4));
+        assert_eq!(skip_name(&[3, b'a'], 0), None);
+    }
+
+    /// Builds a response to `query` with the given answers, each a record type and its data, using a pointer back to the question for the names.
+    fn dns_response(query: &[u8], answers: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut response = query.to_vec();
+        response[2] |= 0x80;
+        response[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+        for (rtype, rdata) in answers {
+            response.extend_from_slice(&[0xc0, 12]);
+            response.extend_from_slice(&rtype.to_be_bytes());
+            response.extend_from_slice(&[0, 1, 0, 0, 1, 0]);
+            response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            response.extend_from_slice(rdata);
+        }
+        response
+    }
+
+    #[test]
+    fn query_and_answers() {
+        let query = dns_query("www.example.com.", RTYPE_A).unwrap();
+        assert_eq!(
+            &query[12..],
+            b"\x03www\x07example\x03com\x00\x00\x01\x00\x01"
+        );
+        assert!(dns_query("bad..name", RTYPE_A).is_err());
+
+        // a CNAME comes first, and is skipped
+        let cname = [3, b'w', b'e', b'b', 0xc0, 16];
+        let response = dns_response(&query, &[(5, &cname), (RTYPE_A, &[192, 0, 2, 7])]);
+        assert_eq!(
+            first_address(&response, RTYPE_A),
+            Some("192.0.2.7".parse().unwrap())
+        );
+        assert_eq!(first_address(&response, RTYPE_AAAA), None);
+
+        let query = dns_query("example.com", RTYPE_AAAA).unwrap();
+        let v6: Ipv6Addr = "2001:db8::7".parse().unwrap();
+        let response = dns_response(&query, &[(RTYPE_AAAA, &v6.octets())]);
+        assert_eq!(first_address(&response, RTYPE_AAAA), Some(v6.into()));
+        assert_eq!(
+            first_address(&response[..response.len() - 1], RTYPE_AAAA),
+            None
+        );
+    }
+}
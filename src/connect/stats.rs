@@ -1,12 +1,18 @@
 pub mod gatherer;
 
 use std::{
-    sync::atomic::{AtomicU64, Ordering},
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Context;
 use async_trait::async_trait;
+use clone_macro::clone;
 use itertools::Itertools;
 use smol_str::SmolStr;
 use smolscale::reaper::TaskReaper;
@@ -18,37 +24,48 @@ use nanorpc::RpcService;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
-use super::ConnectContext;
+use super::{
+    acl::ListenerKind, admission::AdmissionStats, connections::ConnectionInfo,
+    proxy_users::UserStats, routing::RouteDecision, shaping::BandwidthLimits,
+    supervisor::SubsystemHealth, ConnectContext,
+};
 
 /// The main stats-serving thread.
-pub async fn serve_stats_loop(ctx: ConnectContext) -> anyhow::Result<()> {
-    smol::unblock(move || {
+pub async fn serve_stats_loop(ctx: ConnectContext, addr: SocketAddr) -> anyhow::Result<()> {
+    let server = Arc::new(
+        tiny_http::Server::http(addr)
+            .ok()
+            .context("could not start listening")?,
+    );
+    // the blocking loop below does not notice when this task is dropped, so wake it up to release the port
+    scopeguard::defer!(server.unblock());
+    smol::unblock(clone!([server], move || {
         let reaper = TaskReaper::new();
-        let ctx = ctx.clone();
-        loop {
-            let server = tiny_http::Server::http(ctx.opt.stats_listen)
-                .ok()
-                .context("could not start listening")?;
-
-            for mut request in server.incoming_requests() {
-                let ctx = ctx.clone();
-                reaper.attach(smolscale::spawn(async move {
-                    if let Ok(key) = std::env::var("GEPH_RPC_KEY") {
-                        if !request.url().contains(&key) {
-                            anyhow::bail!("missing rpc key")
-                        }
+        for mut request in server.incoming_requests() {
+            let ctx = ctx.clone();
+            reaper.attach(smolscale::spawn(async move {
+                if let Some(&client) = request.remote_addr() {
+                    if !ctx.permit(ListenerKind::Stats, client) {
+                        request.respond(tiny_http::Response::empty(403))?;
+                        return Ok(());
                     }
-                    let mut s = String::new();
-                    request.as_reader().read_to_string(&mut s)?;
-                    let resp = StatsControlService(StatsControlProtocolImpl { ctx })
-                        .respond_raw(serde_json::from_str(&s)?)
-                        .await;
-                    request.respond(tiny_http::Response::from_data(serde_json::to_vec(&resp)?))?;
-                    anyhow::Ok(())
-                }));
-            }
+                }
+                if let Ok(key) = std::env::var("GEPH_RPC_KEY") {
+                    if !request.url().contains(&key) {
+                        anyhow::bail!("missing rpc key")
+                    }
+                }
+                let mut s = String::new();
+                request.as_reader().read_to_string(&mut s)?;
+                let resp = StatsControlService(StatsControlProtocolImpl { ctx })
+                    .respond_raw(serde_json::from_str(&s)?)
+                    .await;
+                request.respond(tiny_http::Response::from_data(serde_json::to_vec(&resp)?))?;
+                anyhow::Ok(())
+            }));
         }
-    })
+        anyhow::Ok(())
+    }))
     .await
 }
 
@@ -63,6 +80,18 @@ pub struct BasicStats {
     pub address: SmolStr,
 }
 
+/// The latest basic statistics, or None if the tunnel has not connected yet.
+pub fn latest_basic_stats() -> Option<BasicStats> {
+    let stats = STATS_GATHERER.all_items().last().cloned()?;
+    Some(BasicStats {
+        address: stats.endpoint,
+        protocol: stats.protocol,
+        last_ping: stats.ping.as_secs_f32() * 1000.0,
+        total_recv_bytes: STATS_RECV_BYTES.load(Ordering::Relaxed) as f32,
+        total_sent_bytes: STATS_SEND_BYTES.load(Ordering::Relaxed) as f32,
+    })
+}
+
 #[derive(Clone)]
 struct StatsControlProtocolImpl {
     ctx: ConnectContext,
@@ -78,16 +107,8 @@ impl StatsControlProtocol for StatsControlProtocolImpl {
     /// Obtains statistics.
     async fn basic_stats(&self) -> BasicStats {
         loop {
-            let stats = STATS_GATHERER.all_items().last().cloned();
-
-            if let Some(stats) = stats {
-                return BasicStats {
-                    address: stats.endpoint,
-                    protocol: stats.protocol,
-                    last_ping: stats.ping.as_secs_f32() * 1000.0,
-                    total_recv_bytes: STATS_RECV_BYTES.load(Ordering::Relaxed) as f32,
-                    total_sent_bytes: STATS_SEND_BYTES.load(Ordering::Relaxed) as f32,
-                };
+            if let Some(stats) = latest_basic_stats() {
+                return stats;
             }
             smol::Timer::after(Duration::from_millis(100)).await;
         }
@@ -165,11 +186,73 @@ impl StatsControlProtocol for StatsControlProtocolImpl {
         }
     }
 
+    /// Obtains the health of every subsystem: each listener, and the VPN.
+    async fn subsystem_health(&self) -> BTreeMap<String, SubsystemHealth> {
+        self.ctx.subsystem_health()
+    }
+
+    /// Obtains how much each proxy user has sent and received.
+    async fn user_stats(&self) -> BTreeMap<String, UserStats> {
+        self.ctx.users.stats()
+    }
+
+    /// Lists every open proxied connection.
+    async fn connections(&self) -> Vec<ConnectionInfo> {
+        self.ctx.connections.list()
+    }
+
+    /// Closes the proxied connection with the given ID, returning whether there was one.
+    async fn close_connection(&self, id: u64) -> bool {
+        self.ctx.connections.close(id)
+    }
+
+    /// Says where a proxied connection to a host would go, and which routing rule decides it.
+    async fn check_route(&self, host: String) -> RouteDecision {
+        self.ctx.route(&host)
+    }
+
+    /// Obtains how busy the local listeners are, and how much they have turned away.
+    async fn admission_stats(&self) -> AdmissionStats {
+        self.ctx.admission.stats()
+    }
+
+    /// Obtains the bandwidth limits currently in effect, in KiB/s.
+    async fn bandwidth_limits(&self) -> BandwidthLimits {
+        self.ctx.bandwidth_limits()
+    }
+
+    /// Changes the bandwidth limits until the configuration is next reloaded, returning whether they were valid.
+    async fn set_bandwidth_limits(&self, limits: BandwidthLimits) -> bool {
+        match self.ctx.set_bandwidth_limits(limits) {
+            Ok(()) => {
+                log::info!("bandwidth limits changed to {:?}", limits);
+                true
+            }
+            Err(err) => {
+                log::warn!("not changing bandwidth limits: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Reloads the configuration, like SIGHUP does, returning whether the new configuration is in effect.
+    async fn reload_config(&self) -> bool {
+        match self.ctx.request_reload().await {
+            Ok(()) => true,
+            Err(err) => {
+                log::warn!("reload_config failed: {:#}", err);
+                false
+            }
+        }
+    }
+
     /// Turns off the daemon.
     async fn kill(&self) -> bool {
-        smolscale::spawn(async {
+        let ctx = self.ctx.clone();
+        smolscale::spawn(async move {
+            // give the response a chance to go out before the stats listener closes
             smol::Timer::after(Duration::from_millis(300)).await;
-            std::process::exit(0);
+            ctx.request_shutdown();
         })
         .detach();
         true
@@ -191,6 +274,33 @@ pub trait StatsControlProtocol {
     /// Obtains time-series statistics.
     async fn timeseries_stats(&self, series: Timeseries) -> Vec<(u64, f32)>;
 
+    /// Obtains the health of every subsystem: each listener, and the VPN.
+    async fn subsystem_health(&self) -> BTreeMap<String, SubsystemHealth>;
+
+    /// Obtains how much each proxy user has sent and received.
+    async fn user_stats(&self) -> BTreeMap<String, UserStats>;
+
+    /// Lists every open proxied connection.
+    async fn connections(&self) -> Vec<ConnectionInfo>;
+
+    /// Closes the proxied connection with the given ID, returning whether there was one.
+    async fn close_connection(&self, id: u64) -> bool;
+
+    /// Says where a proxied connection to a host would go, and which routing rule decides it.
+    async fn check_route(&self, host: String) -> RouteDecision;
+
+    /// Obtains how busy the local listeners are, and how much they have turned away.
+    async fn admission_stats(&self) -> AdmissionStats;
+
+    /// Obtains the bandwidth limits currently in effect, in KiB/s.
+    async fn bandwidth_limits(&self) -> BandwidthLimits;
+
+    /// Changes the bandwidth limits until the configuration is next reloaded, returning whether they were valid.
+    async fn set_bandwidth_limits(&self, limits: BandwidthLimits) -> bool;
+
+    /// Reloads the configuration, like SIGHUP does, returning whether the new configuration is in effect.
+    async fn reload_config(&self) -> bool;
+
     /// Turns off the daemon.
     async fn kill(&self) -> bool;
 }
@@ -0,0 +1,198 @@
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use smolscale::immortal::Immortal;
+
+/// The health of one supervised subsystem, as reported over the stats RPC.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum SubsystemHealth {
+    /// Up, and has not failed recently.
+    Running,
+    /// Failed, and waiting out its backoff before the next attempt.
+    Failed {
+        error: String,
+        consecutive_failures: u32,
+        retry_in_secs: f32,
+    },
+    /// Started again after failing, but not yet up long enough to count as healthy.
+    Restarting {
+        last_error: String,
+        consecutive_failures: u32,
+    },
+}
+
+/// The health of every running subsystem, by name.
+pub type HealthRegistry = Arc<DashMap<String, SubsystemHealth>>;
+
+/// An attempt that stays up this long resets the backoff.
+const HEALTHY_AFTER: Duration = Duration::from_secs(30);
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A subsystem that gets restarted with exponential backoff whenever it fails, independently of everything else. Dropping it stops the subsystem and removes it from the registry.
+pub struct Supervisor {
+    name: String,
+    registry: HealthRegistry,
+    task: Option<Immortal>,
+}
+
+impl Supervisor {
+    /// Starts supervising a subsystem. The subsystem should run forever; returning at all, even successfully, counts as a failure.
+    pub fn spawn<F: Future<Output = anyhow::Result<()>> + Send + 'static>(
+        name: String,
+        registry: HealthRegistry,
+        mut run: impl FnMut() -> F + Send + 'static,
+    ) -> Self {
+        let task = Immortal::spawn({
+            let name = name.clone();
+            let registry = registry.clone();
+            async move {
+                let mut consecutive_failures = 0u32;
+                let mut last_error = String::new();
+                loop {
+                    registry.insert(
+                        name.clone(),
+                        if consecutive_failures == 0 {
+                            SubsystemHealth::Running
+                        } else {
+                            SubsystemHealth::Restarting {
+                                last_error: last_error.clone(),
+                                consecutive_failures,
+                            }
+                        },
+                    );
+                    let started = Instant::now();
+                    let result = smol::future::race(run(), async {
+                        // promote back to running once the attempt has proven itself
+                        smol::Timer::after(HEALTHY_AFTER).await;
+                        registry.insert(name.clone(), SubsystemHealth::Running);
+                        smol::future::pending().await
+                    })
+                    .await;
+                    let error = match result {
+                        Ok(()) => anyhow::anyhow!("stopped unexpectedly"),
+                        Err(err) => err,
+                    };
+                    if started.elapsed() >= HEALTHY_AFTER {
+                        consecutive_failures = 0;
+                    }
+                    consecutive_failures += 1;
+                    last_error = format!("{:#}", error);
+
+                    let backoff = backoff(consecutive_failures);
+                    log::error!(
+                        "{} failed ({} in a row), restarting in {:?}: {:?}",
+                        name,
+                        consecutive_failures,
+                        backoff,
+                        error
+                    );
+                    registry.insert(
+                        name.clone(),
+                        SubsystemHealth::Failed {
+                            error: last_error.clone(),
+                            consecutive_failures,
+                            retry_in_secs: backoff.as_secs_f32(),
+                        },
+                    );
+                    smol::Timer::after(backoff).await;
+                }
+            }
+        });
+        Self {
+            name,
+            registry,
+            task: Some(task),
+        }
+    }
+
+    /// Stops the subsystem, waiting until it has fully stopped running.
+    pub async fn cancel(mut self) {
+        if let Some(task) = self.task.take() {
+            task.cancel().await;
+        }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.registry.remove(&self.name);
+    }
+}
+
+/// Exponential backoff with jitter, starting at a second and capped at a minute.
+fn backoff(consecutive_failures: u32) -> Duration {
+    let base = MIN_BACKOFF
+        .saturating_mul(1 << consecutive_failures.saturating_sub(1).min(6))
+        .min(MAX_BACKOFF);
+    base.mul_f64(0.5 + fastrand::f64() / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        for (failures, base) in [(1, 1), (2, 2), (3, 4), (6, 32), (7, 60), (100, 60)] {
+            let base = Duration::from_secs(base);
+            for _ in 0..100 {
+                let backoff = backoff(failures);
+                assert!(
+                    backoff >= base / 2 && backoff <= base,
+                    "{:?} after {} failures",
+                    backoff,
+                    failures
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn failures_are_reported_and_restarted() {
+        let registry = HealthRegistry::default();
+        let attempts = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let supervisor = Supervisor::spawn("test".into(), registry.clone(), {
+            let attempts = attempts.clone();
+            move || {
+                let attempt = attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                async move {
+                    if attempt == 0 {
+                        anyhow::bail!("first attempt fails")
+                    }
+                    smol::future::pending().await
+                }
+            }
+        });
+        smol::block_on(async {
+            smol::Timer::after(Duration::from_millis(100)).await;
+            match registry.get("test").map(|health| health.clone()) {
+                Some(SubsystemHealth::Failed {
+                    error,
+                    consecutive_failures: 1,
+                    ..
+                }) => assert_eq!(error, "first attempt fails"),
+                other => panic!("expected a failure, got {:?}", other),
+            }
+            // the first backoff is at most a second
+            smol::Timer::after(Duration::from_millis(1100)).await;
+            assert!(matches!(
+                registry.get("test").map(|health| health.clone()),
+                Some(SubsystemHealth::Restarting {
+                    consecutive_failures: 1,
+                    ..
+                })
+            ));
+        });
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 2);
+        drop(supervisor);
+        assert!(registry.get("test").is_none());
+    }
+}
@@ -1,40 +1,53 @@
 use bytes::Bytes;
 
+use clone_macro::clone;
 use derivative::Derivative;
-use geph5_broker_protocol::Credential;
-use geph5_client::{BridgeMode, BrokerSource, Config};
-use geph_nat::GephNat;
+use ed25519_dalek::VerifyingKey;
+use event_listener::Event;
+use geph5_broker_protocol::{BrokerClient, ExitDescriptor};
+use geph5_client::{BridgeMode, Config, ExitConstraint};
+use isocountry::CountryCode;
 use parking_lot::RwLock;
 
 use sillad::Pipe;
 use smol::{
     channel::{Receiver, Sender},
+    future::FutureExt,
     Task,
 };
 use smol_str::SmolStr;
-use std::{
-    net::SocketAddr,
-    time::{Duration, SystemTime},
-};
+use smol_timeout::TimeoutExt;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use itertools::Itertools;
 use stdcode::StdcodeSerializeExt;
 use tmelcrypt::Hashable;
 
 use sosistab2::Stream;
-use std::sync::Arc;
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
-use std::net::Ipv4Addr;
+use crate::config::ConnectOpt;
 
-use crate::config::{ConnectOpt, GEPH5_CONFIG_TEMPLATE};
+use super::{
+    hooks::{HookConn, Hooks},
+    routing::Rejected,
+    stats::{gatherer::StatItem, STATS_GATHERER},
+    udp_nat::{self, UdpNat},
+};
 
-use super::stats::{gatherer::StatItem, STATS_GATHERER};
+/// The longest wait between attempts to start the geph5 client.
+const MAX_START_BACKOFF: Duration = Duration::from_secs(60);
 
-#[derive(Clone)]
-pub struct BinderTunnelParams {
-    pub exit_server: Option<String>,
-    pub use_bridges: bool,
-    pub force_bridge: Option<Ipv4Addr>,
-    pub force_protocol: Option<String>,
-}
+/// How long starting the tunnel waits for the broker's exit list to check the exit options against, before leaving that to the background.
+const STARTUP_EXIT_CHECK: Duration = Duration::from_secs(10);
+
+/// How long an exit picked here may stay unreachable before it is picked again, possibly for another exit.
+const REPICK_AFTER: Duration = Duration::from_secs(120);
 
 #[derive(Clone)]
 struct TunnelCtx {
@@ -63,26 +76,141 @@ impl ConnectionStatus {
 /// A sosistab Session is *a single end-to-end connection between a client and a server.*
 /// This can be thought of as analogous to TcpStream, except all reads and writes are datagram-based and unreliable.
 pub struct ClientTunnel {
-    client: geph5_client::Client,
-    _stat_reporter: Task<()>,
+    /// The geph5 client, set once the exit has been picked and the client started in the background.
+    client: Arc<CurrentClient>,
+    down_since: Arc<RwLock<Option<Instant>>>,
+    udp_nat: Arc<UdpNat>,
+    recv_vpn_incoming: Receiver<Bytes>,
+    _runner: Task<()>,
 }
 
-impl ClientTunnel {
-    /// Creates a new ClientTunnel.
-    pub fn new(opt: ConnectOpt) -> Self {
-        let (username, password) = match &opt.auth.auth_kind {
-            Some(crate::config::AuthKind::AuthPassword { username, password }) => {
-                (username.clone(), password.clone())
+/// The geph5 client that is running right now. It is replaced whenever an exit picked here is picked again.
+#[derive(Default)]
+struct CurrentClient {
+    client: RwLock<Option<Arc<geph5_client::Client>>>,
+    on_set: Event,
+}
+
+impl CurrentClient {
+    fn get(&self) -> Option<Arc<geph5_client::Client>> {
+        self.client.read().clone()
+    }
+
+    /// Waits until some client is running, and returns it.
+    async fn wait(&self) -> Arc<geph5_client::Client> {
+        loop {
+            let listener = self.on_set.listen();
+            if let Some(client) = self.get() {
+                return client;
             }
-            _ => todo!(),
-        };
-        let mut config = GEPH5_CONFIG_TEMPLATE.clone();
-        config.credentials = Credential::LegacyUsernamePassword { username, password };
-        config.bridge_mode = if opt.use_bridges {
-            BridgeMode::ForceBridges
-        } else {
-            BridgeMode::Auto
-        };
+            listener.await;
+        }
+    }
+
+    fn set(&self, client: Arc<geph5_client::Client>) {
+        *self.client.write() = Some(client);
+        self.on_set.notify(usize::MAX);
+    }
+}
+
+/// The error for streams refused because the tunnel has been down for longer than the grace period.
+#[derive(Debug, thiserror::Error)]
+#[error("tunnel has been down for {0:?}")]
+pub struct TunnelDown(pub Duration);
+
+/// The error for destinations whose names did not resolve to any address.
+#[derive(Debug, thiserror::Error)]
+#[error("cannot resolve {0}")]
+pub struct Unresolvable(pub String);
+
+/// The error for exit options that no exit in the broker's exit list fits.
+#[derive(Debug, thiserror::Error)]
+#[error("no exit fits {0}")]
+pub struct NoMatchingExit(String);
+
+/// The exit that geph5 is to use, as picked from the exit options.
+struct PickedExit {
+    constraint: ExitConstraint,
+    /// The exit itself, when it was picked here rather than by geph5, which only reports a placeholder for such exits.
+    exit: Option<ExitDescriptor>,
+    /// The relay to the exit through the outbound proxy, if there is one.
+    _relay: Option<Task<()>>,
+}
+
+/// Why a stream could not be opened, in the terms that proxy clients can act on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpenFailure {
+    /// The tunnel has been down past the grace period; see [`TunnelDown`].
+    TunnelDown,
+    /// Nothing answered in time.
+    TimedOut,
+    /// The destination's name did not resolve, see [`Unresolvable`], or there is no route to it.
+    Unreachable,
+    /// The destination refused the connection.
+    Refused,
+    /// A routing rule rejected the destination, see [`Rejected`], or a proxy forbade it.
+    Rejected,
+    /// Anything else, such as the session dying while the stream was being opened.
+    Other,
+}
+
+/// The start of each message that geph5 (0.2.0-alpha.10) reports a failure with, where it has no error type to go by. Only whole errors starting with one of these count, so a change of wording upstream shows up as the failure no longer being classified.
+const GEPH5_FAILURE_MESSAGES: &[(&str, OpenFailure)] = &[
+    // geph5-client src/client_inner.rs:198, the context on the dial, mux and authentication taking over 15 seconds
+    ("overall dial/mux/auth timeout", OpenFailure::TimedOut),
+    // geph5-client src/client_inner.rs:320, followed by the exit's reason
+    (
+        "exit rejected our authentication attempt: ",
+        OpenFailure::Rejected,
+    ),
+];
+
+impl OpenFailure {
+    /// Classifies an error from opening a stream, whether through the tunnel or directly, looking through whatever wraps it. Error types anywhere in the chain come first; only then are the messages geph5 uses for untyped failures, such as its timeouts and an exit refusing us, looked for.
+    pub fn of(err: &anyhow::Error) -> Self {
+        for cause in err.chain() {
+            if cause.is::<TunnelDown>() {
+                return Self::TunnelDown;
+            }
+            if cause.is::<Rejected>() {
+                return Self::Rejected;
+            }
+            if cause.is::<Unresolvable>() {
+                return Self::Unreachable;
+            }
+            if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+                match err.kind() {
+                    ErrorKind::TimedOut => return Self::TimedOut,
+                    ErrorKind::ConnectionRefused => return Self::Refused,
+                    ErrorKind::PermissionDenied => return Self::Rejected,
+                    ErrorKind::HostUnreachable
+                    | ErrorKind::NetworkUnreachable
+                    | ErrorKind::NotFound
+                    | ErrorKind::AddrNotAvailable => return Self::Unreachable,
+                    _ => {}
+                }
+            }
+        }
+        err.chain()
+            .find_map(|cause| {
+                let message = cause.to_string();
+                GEPH5_FAILURE_MESSAGES
+                    .iter()
+                    .find(|(start, _)| message.starts_with(start))
+                    .map(|(_, failure)| *failure)
+            })
+            .unwrap_or(Self::Other)
+    }
+}
+
+impl ClientTunnel {
+    /// Creates a new ClientTunnel. Exit options that no exit fits are an error. Picking the exit can take the broker, so if the broker does not answer right away, it happens in the background, retrying until it works; until then the tunnel counts as connecting.
+    pub async fn new(opt: ConnectOpt) -> anyhow::Result<Self> {
+        let (mut config, broker_relay) = opt.common.geph5_config()?;
+        config.credentials = opt.auth.geph5_credential()?;
+        config.bridge_mode = bridge_mode(&opt);
+        std::fs::create_dir_all(&opt.auth.credential_cache)
+            .context("cannot create credential cache directory")?;
         config.cache = Some(
             opt.auth
                 .credential_cache
@@ -90,55 +218,745 @@ impl ClientTunnel {
                 .join(format!("cache-{}.db", opt.auth.stdcode().hash())),
         );
         log::debug!("cache path: {:?}", config.cache);
-        let client = geph5_client::Client::start(config);
-        let handle = client.control_client();
-        let stat_reporter = smolscale::spawn(async move {
+        // retrying cannot fix exit options that fit no exit, so only a broker that cannot be reached yet is left to the background
+        let picked = match pick_exit(&opt).timeout(STARTUP_EXIT_CHECK).await {
+            Some(Ok(picked)) => Some(picked),
+            Some(Err(err)) if err.is::<NoMatchingExit>() => return Err(err),
+            Some(Err(err)) => {
+                log::warn!(
+                    "cannot pick an exit yet, retrying in the background: {:?}",
+                    err
+                );
+                None
+            }
+            None => {
+                log::warn!(
+                    "cannot pick an exit within {:?}, retrying in the background",
+                    STARTUP_EXIT_CHECK
+                );
+                None
+            }
+        };
+        let client = Arc::new(CurrentClient::default());
+        let down_since = Arc::new(RwLock::new(Some(Instant::now())));
+        let udp_nat = Arc::new(UdpNat::default());
+        let (send_vpn_incoming, recv_vpn_incoming) = smol::channel::bounded(1000);
+        let runner = smolscale::spawn(clone!([client, down_since, udp_nat], async move {
+            // the broker relay lives as long as the tunnel, and an exit relay as long as its client
+            let _broker_relay = broker_relay;
+            let hooks = Hooks::new(&opt);
+            // the last connection seen, kept across reconnects and new clients, so that exit changes can be noticed
+            let mut last_conn = None;
+            let mut picked = picked;
             loop {
-                smol::Timer::after(Duration::from_secs(1)).await;
-                let info = handle.conn_info().await.unwrap();
-                let recv_bytes = handle.stat_num("total_rx_bytes".into()).await.unwrap();
-                let send_bytes = handle.stat_num("total_tx_bytes".into()).await.unwrap();
-                match info {
-                    geph5_client::ConnInfo::Connecting => {}
-                    geph5_client::ConnInfo::Connected(conn) => STATS_GATHERER.push(StatItem {
-                        time: SystemTime::now(),
-                        endpoint: conn.bridge.into(),
-                        protocol: conn.protocol.into(),
-                        ping: Duration::from_millis(100),
-                        send_bytes: send_bytes as u64,
-                        recv_bytes: recv_bytes as u64,
-                    }),
-                }
+                let (started, picked_now) = start_client(&opt, config.clone(), picked.take()).await;
+                client.set(started.clone());
+                run_client(
+                    started,
+                    picked_now.exit.as_ref(),
+                    &hooks,
+                    &mut last_conn,
+                    &down_since,
+                    &udp_nat,
+                    &send_vpn_incoming,
+                )
+                .await;
+                log::warn!(
+                    "exit unreachable for {:?}, picking an exit again",
+                    REPICK_AFTER
+                );
             }
-        });
-        Self {
+        }));
+        Ok(Self {
             client,
-            _stat_reporter: stat_reporter,
-        }
+            down_since,
+            udp_nat,
+            recv_vpn_incoming,
+            _runner: runner,
+        })
     }
 
-    /// Returns the current connection status.
+    /// Returns the current connection status. If geph5 cannot be asked for it, the tunnel counts as connecting.
     pub async fn status(&self) -> ConnectionStatus {
-        let conn_info = self.client.control_client().conn_info().await.unwrap();
-        match conn_info {
-            geph5_client::ConnInfo::Connecting => ConnectionStatus::Connecting,
-            geph5_client::ConnInfo::Connected(info) => ConnectionStatus::Connected {
+        let Some(client) = self.client.get() else {
+            return ConnectionStatus::Connecting;
+        };
+        match client.control_client().conn_info().await {
+            Ok(geph5_client::ConnInfo::Connected(info)) => ConnectionStatus::Connected {
                 protocol: info.protocol.into(),
                 address: info.bridge.into(),
             },
+            Ok(geph5_client::ConnInfo::Connecting) => ConnectionStatus::Connecting,
+            Err(err) => {
+                log::debug!("cannot get the tunnel status: {:?}", err);
+                ConnectionStatus::Connecting
+            }
         }
     }
 
     /// Returns a sosistab stream to the given remote host.
     pub async fn connect_stream(&self, remote: &str) -> anyhow::Result<Box<dyn Pipe>> {
-        self.client.open_conn(remote).await
+        self.client.wait().await.open_conn(remote).await
+    }
+
+    /// How long the tunnel has been trying to (re)connect, or None if it is connected.
+    pub fn down_for(&self) -> Option<Duration> {
+        self.down_since.read().map(|since| since.elapsed())
+    }
+
+    /// Like `connect_stream`, but fails fast with [`TunnelDown`] once the tunnel has been down for longer than `grace`, whether that is already the case or happens while waiting.
+    pub async fn connect_stream_within(
+        &self,
+        remote: &str,
+        grace: Duration,
+    ) -> anyhow::Result<Box<dyn Pipe>> {
+        let watchdog = async {
+            loop {
+                if let Some(down_for) = self.down_for().filter(|d| *d > grace) {
+                    return Err(TunnelDown(down_for).into());
+                }
+                smol::Timer::after(Duration::from_millis(250)).await;
+            }
+        };
+        watchdog.or(self.connect_stream(remote)).await
     }
 
     pub async fn send_vpn(&self, msg: &[u8]) -> anyhow::Result<()> {
-        self.client.send_vpn_packet(msg.to_vec().into()).await
+        self.client
+            .wait()
+            .await
+            .send_vpn_packet(msg.to_vec().into())
+            .await
     }
 
     pub async fn recv_vpn(&self) -> anyhow::Result<Bytes> {
-        self.client.recv_vpn_packet().await
+        Ok(self.recv_vpn_incoming.recv().await?)
+    }
+
+    /// Binds a UDP socket whose datagrams go through the tunnel's packet path, sharing it with the VPN.
+    pub fn bind_udp(self: &Arc<Self>) -> anyhow::Result<TunnelUdpSocket> {
+        let (port, recv_reply) = self.udp_nat.bind()?;
+        Ok(TunnelUdpSocket {
+            tunnel: self.clone(),
+            port,
+            recv_reply,
+        })
+    }
+}
+
+/// A UDP socket bound through the tunnel, created by [`ClientTunnel::bind_udp`]. Dropping it releases its port.
+pub struct TunnelUdpSocket {
+    tunnel: Arc<ClientTunnel>,
+    port: u16,
+    recv_reply: Receiver<(SocketAddr, Bytes)>,
+}
+
+impl TunnelUdpSocket {
+    /// Sends a datagram to the given address on the other side of the tunnel.
+    pub async fn send_to(&self, payload: &[u8], dest: SocketAddr) -> anyhow::Result<()> {
+        let pkt = udp_nat::encapsulate(self.port, dest, payload);
+        self.tunnel.client.wait().await.send_vpn_packet(pkt).await
+    }
+
+    /// Receives a datagram, along with the address it came from.
+    pub async fn recv_from(&self) -> anyhow::Result<(SocketAddr, Bytes)> {
+        Ok(self.recv_reply.recv().await?)
+    }
+}
+
+impl Drop for TunnelUdpSocket {
+    fn drop(&mut self) {
+        self.tunnel.udp_nat.unbind(self.port);
+    }
+}
+
+/// Starts the geph5 client with the exit already picked, or else picks it, retrying with backoff for as long as that fails, such as while the broker cannot be reached. The picked exit is returned too, and its relay, if any, must be kept for as long as the client runs.
+async fn start_client(
+    opt: &ConnectOpt,
+    mut config: Config,
+    mut picked: Option<PickedExit>,
+) -> (Arc<geph5_client::Client>, PickedExit) {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let result = match picked.take() {
+            Some(picked) => Ok(picked),
+            None => pick_exit(opt).await,
+        };
+        match result {
+            Ok(picked) => {
+                log::info!("exit constraint: {:?}", picked.constraint);
+                config.exit_constraint = picked.constraint.clone();
+                return (Arc::new(geph5_client::Client::start(config)), picked);
+            }
+            Err(err) => {
+                log::warn!("cannot pick an exit, retrying in {:?}: {:?}", backoff, err);
+                smol::Timer::after(backoff).await;
+                backoff = (backoff * 2).min(MAX_START_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Follows a started geph5 client: reporting its status, running the hooks, and sorting the packets coming out of it. `picked_exit` is the exit picked for geph5, if it was not left to geph5 to pick. Such an exit is never swapped for another by geph5, so once it has been unreachable for [`REPICK_AFTER`], this returns for the exit to be picked again.
+async fn run_client(
+    client: Arc<geph5_client::Client>,
+    picked_exit: Option<&ExitDescriptor>,
+    hooks: &Hooks,
+    last_conn: &mut Option<HookConn>,
+    down_since: &RwLock<Option<Instant>>,
+    udp_nat: &UdpNat,
+    send_vpn_incoming: &Sender<Bytes>,
+) {
+    let handle = client.control_client();
+    let stat_reporter = async {
+        // a client is only replaced while the tunnel is down, so every client starts out disconnected
+        let mut connected = false;
+        let mut up_at = Instant::now();
+        loop {
+            smol::Timer::after(Duration::from_secs(1)).await;
+            // geph5 failing to answer is taken as the tunnel being down, rather than a reason to stop following it
+            let info = match handle.conn_info().await {
+                Ok(info) => info,
+                Err(err) => {
+                    log::warn!("cannot get the tunnel status: {:?}", err);
+                    geph5_client::ConnInfo::Connecting
+                }
+            };
+            match info {
+                geph5_client::ConnInfo::Connecting => {
+                    down_since.write().get_or_insert_with(Instant::now);
+                    if connected {
+                        connected = false;
+                        if let Some(last_conn) = last_conn.as_ref() {
+                            hooks.disconnected(last_conn);
+                        }
+                    }
+                    if picked_exit.is_some() && up_at.elapsed() > REPICK_AFTER {
+                        return;
+                    }
+                }
+                geph5_client::ConnInfo::Connected(conn) => {
+                    *down_since.write() = None;
+                    up_at = Instant::now();
+                    // geph5 only knows exits picked for it by the address it was given
+                    let exit = picked_exit.unwrap_or(&conn.exit);
+                    let current = HookConn {
+                        protocol: conn.protocol.clone(),
+                        bridge: conn.bridge.clone(),
+                        exit: exit.c2e_listen.ip(),
+                        exit_country: exit.country.alpha2().to_string(),
+                        exit_city: exit.city.clone(),
+                    };
+                    if last_conn.as_ref().map(|c| c.exit) != Some(current.exit) {
+                        log::info!(
+                            "connected to exit {} ({}/{}) via {} bridge {}",
+                            current.exit,
+                            current.exit_country,
+                            current.exit_city,
+                            current.protocol,
+                            current.bridge
+                        );
+                        if let Some(previous) = last_conn.as_ref() {
+                            hooks.exit_changed(previous, &current);
+                        }
+                    }
+                    if !connected {
+                        connected = true;
+                        hooks.connected(&current);
+                    }
+                    *last_conn = Some(current);
+                    let bytes = async {
+                        anyhow::Ok((
+                            handle.stat_num("total_tx_bytes".into()).await?,
+                            handle.stat_num("total_rx_bytes".into()).await?,
+                        ))
+                    };
+                    match bytes.await {
+                        Ok((send_bytes, recv_bytes)) => STATS_GATHERER.push(StatItem {
+                            time: SystemTime::now(),
+                            endpoint: conn.bridge.into(),
+                            protocol: conn.protocol.into(),
+                            ping: Duration::from_millis(100),
+                            send_bytes: send_bytes as u64,
+                            recv_bytes: recv_bytes as u64,
+                        }),
+                        Err(err) => log::warn!("cannot get the tunnel statistics: {:?}", err),
+                    }
+                }
+            }
+        }
+    };
+    // packets coming out of the tunnel are either replies to datagrams sent through the NAT, or VPN traffic
+    let vpn_demux = async {
+        while let Ok(pkt) = client.recv_vpn_packet().await {
+            if let Some(pkt) = udp_nat.deliver(pkt) {
+                let _ = send_vpn_incoming.try_send(pkt);
+            }
+        }
+    };
+    stat_reporter.race(vpn_demux).await
+}
+
+/// Translates the bridge options into a geph5 bridge mode.
+fn bridge_mode(opt: &ConnectOpt) -> BridgeMode {
+    if opt.use_bridges {
+        BridgeMode::ForceBridges
+    } else {
+        BridgeMode::Auto
+    }
+}
+
+/// Picks the exit that the exit options ask for. Where it can, geph5 is left to pick among the exits that fit by itself. Only --exit-server pins one exit, and so does an outbound proxy, since geph5 can then only reach a single exit, through a relay.
+async fn pick_exit(opt: &ConnectOpt) -> anyhow::Result<PickedExit> {
+    let proxy = opt.common.outbound_proxy.as_ref();
+    if proxy.is_none()
+        && opt.exit_server.is_none()
+        && opt.exit_country.is_none()
+        && opt.exit_city.is_none()
+    {
+        return Ok(PickedExit {
+            constraint: ExitConstraint::Auto,
+            exit: None,
+            _relay: None,
+        });
+    }
+
+    let exits = get_exits(opt).await?;
+    let fitting = fitting_exits(opt, &exits)?;
+    if let Some(proxy) = proxy {
+        // like geph5, take the least loaded exit that fits
+        let (pubkey, exit) = fitting
+            .into_iter()
+            .min_by_key(|(_, exit)| (exit.load * 1000.0) as u64)
+            .context("no exits fit")?;
+        let (relay, task) = proxy
+            .relay(exit.c2e_listen)
+            .context("cannot start exit relay")?;
+        log::info!(
+            "reaching exit {} ({}/{}) through {} via {}",
+            exit.c2e_listen.ip(),
+            exit.country.alpha2(),
+            exit.city,
+            proxy,
+            relay
+        );
+        // only a direct connection to the exit can be relayed, which is why the outbound proxy cannot be combined with --use-bridges
+        return Ok(PickedExit {
+            constraint: ExitConstraint::Direct(format!(
+                "{}/{}",
+                relay,
+                hex::encode(pubkey.as_bytes())
+            )),
+            exit: Some(exit.clone()),
+            _relay: Some(task),
+        });
+    }
+
+    let (pubkey, exit) = fitting[0];
+    if opt.exit_server.is_some() {
+        // only a direct constraint pins one particular exit, which is why --exit-server cannot be combined with --use-bridges
+        return Ok(PickedExit {
+            constraint: ExitConstraint::Direct(format!(
+                "{}/{}",
+                exit.c2e_listen,
+                hex::encode(pubkey.as_bytes())
+            )),
+            exit: Some(exit.clone()),
+            _relay: None,
+        });
+    }
+    let constraint = match &opt.exit_city {
+        // the city is spelled the way the exit list does, and its country comes from there if not given
+        Some(_) => ExitConstraint::CountryCity(exit.country, exit.city.clone()),
+        None => ExitConstraint::Country(exit.country),
+    };
+    Ok(PickedExit {
+        constraint,
+        exit: None,
+        _relay: None,
+    })
+}
+
+/// Asks the broker for every exit there is.
+async fn get_exits(opt: &ConnectOpt) -> anyhow::Result<Vec<(VerifyingKey, ExitDescriptor)>> {
+    let broker = BrokerClient::from(opt.common.broker_transport());
+    Ok(broker
+        .get_exits()
+        .await?
+        .map_err(|e| anyhow::anyhow!("broker refused to serve exits: {e}"))?
+        .inner
+        .all_exits)
+}
+
+/// The exits that fit the exit options, of which there is at least one, or else [`NoMatchingExit`]. With --exit-server, that is the one exit it names.
+fn fitting_exits<'a>(
+    opt: &ConnectOpt,
+    exits: &'a [(VerifyingKey, ExitDescriptor)],
+) -> anyhow::Result<Vec<&'a (VerifyingKey, ExitDescriptor)>> {
+    if let Some(exit_server) = &opt.exit_server {
+        return Ok(vec![closest_exit(exits, exit_server)?]);
+    }
+    let country = opt.exit_country.as_deref().map(parse_country).transpose()?;
+    let fitting = exits
+        .iter()
+        .filter(|(_, exit)| country.is_none_or(|country| exit.country == country))
+        .filter(|(_, exit)| {
+            opt.exit_city
+                .as_ref()
+                .is_none_or(|city| exit.city.eq_ignore_ascii_case(city))
+        })
+        .collect_vec();
+    if fitting.is_empty() {
+        let mut wanted = vec![];
+        if let Some(country) = country {
+            wanted.push(format!("--exit-country {}", country.alpha2()));
+        }
+        if let Some(city) = &opt.exit_city {
+            wanted.push(format!("--exit-city {:?}", city));
+        }
+        return Err(NoMatchingExit(format!(
+            "{}; the broker knows exits in {}",
+            wanted.join(" and "),
+            exits
+                .iter()
+                .map(|(_, exit)| format!("{}/{}", exit.city, exit.country.alpha2()))
+                .unique()
+                .join(", ")
+        ))
+        .into());
+    }
+    Ok(fitting)
+}
+
+/// Finds the exit that `exit_server` names. An IP address has to be an exit's own address. Anything else is a host name, like "2.mtl.ca.ngexits.geph.io", and picks the exit with the most similar name. The exit list has no host names, so an exit's names are its address and its location, like "montreal.ca". The name is never looked up, which would tell anyone watching the network which exit we are after.
+fn closest_exit<'a>(
+    exits: &'a [(VerifyingKey, ExitDescriptor)],
+    exit_server: &str,
+) -> anyhow::Result<&'a (VerifyingKey, ExitDescriptor)> {
+    if let Ok(ip) = exit_server.parse::<IpAddr>() {
+        return exits
+            .iter()
+            .find(|(_, exit)| exit.c2e_listen.ip() == ip)
+            .ok_or_else(|| {
+                NoMatchingExit(format!(
+                    "--exit-server {}; the broker knows {}",
+                    ip,
+                    exits
+                        .iter()
+                        .map(|(_, exit)| exit.c2e_listen.ip())
+                        .join(", ")
+                ))
+                .into()
+            });
+    }
+    let host = exit_server.to_lowercase();
+    let (similarity, closest) = exits
+        .iter()
+        .map(|exit| (exit_similarity(&host, &exit.1), exit))
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))
+        .ok_or_else(|| {
+            NoMatchingExit(format!(
+                "--exit-server {:?}; the broker knows no exits",
+                exit_server
+            ))
+        })?;
+    log::info!(
+        "exit server {:?} is most similar to exit {} ({}/{}), with similarity {:.2}",
+        exit_server,
+        closest.1.c2e_listen.ip(),
+        closest.1.country.alpha2(),
+        closest.1.city,
+        similarity
+    );
+    Ok(closest)
+}
+
+/// How similar a lowercase host name is to an exit, from 0 to 1. That is its similarity to the exit's address, or that of any run of its labels to the exit's location, whichever is higher, so that the "mtl.ca" in "2.mtl.ca.ngexits.geph.io" counts for Montreal, Canada.
+fn exit_similarity(host: &str, exit: &ExitDescriptor) -> f64 {
+    let address = exit.c2e_listen.ip().to_string();
+    let location = format!(
+        "{}.{}",
+        exit.city.to_lowercase().replace(' ', ""),
+        exit.country.alpha2().to_lowercase()
+    );
+    let labels = host.split('.').collect_vec();
+    let by_location = (0..labels.len())
+        .flat_map(|start| (start + 1..=labels.len()).map(move |end| (start, end)))
+        .map(|(start, end)| {
+            strsim::normalized_damerau_levenshtein(&labels[start..end].join("."), &location)
+        })
+        .fold(0.0, f64::max);
+    strsim::normalized_damerau_levenshtein(host, &address).max(by_location)
+}
+
+fn parse_country(country: &str) -> anyhow::Result<CountryCode> {
+    CountryCode::for_alpha2_caseless(country)
+        .ok()
+        .with_context(|| format!("invalid exit country code {:?}", country))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use ed25519_dalek::SigningKey;
+    use geph5_broker_protocol::{ExitList, Signed};
+    use smol::io::{AsyncBufReadExt, AsyncWriteExt};
+    use structopt::StructOpt;
+
+    use super::*;
+
+    #[test]
+    fn classify_open_failures() {
+        let io_err = |kind: ErrorKind| anyhow::Error::from(io::Error::from(kind));
+        assert_eq!(
+            OpenFailure::of(&io_err(ErrorKind::TimedOut)),
+            OpenFailure::TimedOut
+        );
+        assert_eq!(
+            OpenFailure::of(&io_err(ErrorKind::ConnectionRefused)),
+            OpenFailure::Refused
+        );
+        assert_eq!(
+            OpenFailure::of(&io_err(ErrorKind::PermissionDenied)),
+            OpenFailure::Rejected
+        );
+        assert_eq!(
+            OpenFailure::of(&io_err(ErrorKind::NetworkUnreachable)),
+            OpenFailure::Unreachable
+        );
+        assert_eq!(
+            OpenFailure::of(&io_err(ErrorKind::BrokenPipe)),
+            OpenFailure::Other
+        );
+        // a lookup failure is only recognized by its type, not by its message
+        assert_eq!(
+            OpenFailure::of(&io::Error::other("failed to lookup address information").into()),
+            OpenFailure::Other
+        );
+        assert_eq!(
+            OpenFailure::of(&anyhow::anyhow!("session died")),
+            OpenFailure::Other
+        );
+    }
+
+    /// An error type that only carries the error it wraps as its source, as dialers and muxes do.
+    #[derive(Debug, thiserror::Error)]
+    #[error("could not dial")]
+    struct DialFailed(#[source] io::Error);
+
+    #[test]
+    fn classify_geph5_errors() {
+        // whitelisted destinations are dialed by geph5 itself, with io errors behind its own wrappers
+        let err = anyhow::Error::from(DialFailed(io::Error::new(
+            ErrorKind::TimedOut,
+            "dial timed out after 10s",
+        )))
+        .context("cannot open stream");
+        assert_eq!(OpenFailure::of(&err), OpenFailure::TimedOut);
+        let err = anyhow::Error::from(DialFailed(ErrorKind::ConnectionRefused.into()));
+        assert_eq!(OpenFailure::of(&err), OpenFailure::Refused);
+        // geph5's own timeouts and refusals are only messages
+        let err = anyhow::Error::from(io::Error::from(ErrorKind::BrokenPipe))
+            .context("overall dial/mux/auth timeout");
+        assert_eq!(OpenFailure::of(&err), OpenFailure::TimedOut);
+        let err = anyhow::anyhow!("exit rejected our authentication attempt: bad token")
+            .context("cannot open stream");
+        assert_eq!(OpenFailure::of(&err), OpenFailure::Rejected);
+        // a session that died while opening is nothing more specific
+        let err = anyhow::anyhow!("receiving on a closed channel");
+        assert_eq!(OpenFailure::of(&err), OpenFailure::Other);
+        // nor are messages that merely mention a timeout or a rejection
+        for message in [
+            "invalid timeout value",
+            "cannot open stream: overall dial/mux/auth timeout",
+            "exit rejected the stream",
+        ] {
+            assert_eq!(
+                OpenFailure::of(&anyhow::anyhow!(message.to_string())),
+                OpenFailure::Other,
+                "{}",
+                message
+            );
+        }
+        // a type anywhere in the chain beats any message
+        let err = anyhow::Error::from(TunnelDown(Duration::from_secs(5)))
+            .context("open connection timeout");
+        assert_eq!(OpenFailure::of(&err), OpenFailure::TunnelDown);
+        let err = anyhow::Error::from(io::Error::new(
+            ErrorKind::ConnectionRefused,
+            "exit rejected the connection",
+        ));
+        assert_eq!(OpenFailure::of(&err), OpenFailure::Refused);
+    }
+
+    #[test]
+    fn classify_through_context() {
+        let err =
+            anyhow::Error::from(TunnelDown(Duration::from_secs(5))).context("cannot open stream");
+        assert_eq!(OpenFailure::of(&err), OpenFailure::TunnelDown);
+        let err = anyhow::Error::from(Unresolvable("nowhere.invalid:80".into())).context("direct");
+        assert_eq!(OpenFailure::of(&err), OpenFailure::Unreachable);
+        let err = anyhow::Error::from(Rejected("REJECT rule".into()));
+        assert_eq!(OpenFailure::of(&err), OpenFailure::Rejected);
+        let err = anyhow::Error::from(io::Error::from(ErrorKind::ConnectionRefused))
+            .context("through the proxy");
+        assert_eq!(OpenFailure::of(&err), OpenFailure::Refused);
+    }
+
+    fn exits() -> Vec<(VerifyingKey, ExitDescriptor)> {
+        [
+            ("192.0.2.1", CountryCode::CAN, "Montreal", 0.5),
+            ("192.0.2.2", CountryCode::CAN, "Toronto", 0.2),
+            ("192.0.2.3", CountryCode::DEU, "Frankfurt", 0.1),
+            ("192.0.2.4", CountryCode::USA, "New York", 0.9),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, (ip, country, city, load))| {
+            let key = ed25519_dalek::SigningKey::from_bytes(&[i as u8; 32]).verifying_key();
+            let exit = ExitDescriptor {
+                c2e_listen: format!("{}:28080", ip).parse().unwrap(),
+                b2e_listen: format!("{}:28081", ip).parse().unwrap(),
+                country,
+                city: city.into(),
+                load,
+                expiry: 0,
+            };
+            (key, exit)
+        })
+        .collect()
+    }
+
+    fn fitting_cities(args: &[&str]) -> anyhow::Result<Vec<String>> {
+        let opt =
+            ConnectOpt::from_iter_safe(std::iter::once("geph4-client").chain(args.iter().copied()))
+                .unwrap();
+        let exits = exits();
+        Ok(fitting_exits(&opt, &exits)?
+            .into_iter()
+            .map(|(_, exit)| exit.city.clone())
+            .collect())
+    }
+
+    #[test]
+    fn exit_server_by_address() {
+        assert_eq!(
+            fitting_cities(&["--exit-server", "192.0.2.2"]).unwrap(),
+            ["Toronto"]
+        );
+        // an address has to be an exit's own, however similar another one is
+        let err = fitting_cities(&["--exit-server", "192.0.2.5"]).unwrap_err();
+        assert!(err.is::<NoMatchingExit>(), "{}", err);
+    }
+
+    #[test]
+    fn exit_server_by_similar_name() {
+        for (name, city) in [
+            ("2.mtl.ca.ngexits.geph.io", "Montreal"),
+            ("MONTREAL.CA", "Montreal"),
+            ("1.toronto.ca.ngexits.geph.io", "Toronto"),
+            ("3.fra.de.ngexits.geph.io", "Frankfurt"),
+            ("newyork.us", "New York"),
+        ] {
+            assert_eq!(
+                fitting_cities(&["--exit-server", name]).unwrap(),
+                [city],
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn exit_country_and_city() {
+        assert_eq!(
+            fitting_cities(&["--exit-country", "ca"]).unwrap(),
+            ["Montreal", "Toronto"]
+        );
+        assert_eq!(
+            fitting_cities(&["--exit-city", "toronto"]).unwrap(),
+            ["Toronto"]
+        );
+        assert_eq!(
+            fitting_cities(&["--exit-country", "CA", "--exit-city", "Montreal"]).unwrap(),
+            ["Montreal"]
+        );
+        assert_eq!(fitting_cities(&[]).unwrap().len(), 4);
+        for args in [
+            &["--exit-country", "FR"][..],
+            &["--exit-city", "Paris"],
+            &["--exit-country", "DE", "--exit-city", "Montreal"],
+        ] {
+            let err = fitting_cities(args).unwrap_err();
+            assert!(err.is::<NoMatchingExit>(), "{:?}: {}", args, err);
+            assert!(err.to_string().contains("Frankfurt/DE"), "{}", err);
+        }
+    }
+
+    /// Answers every broker call on a local port with the [`exits`] fixture, as a broker reached with --broker-addr would.
+    fn fake_broker() -> (SocketAddr, Task<()>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = smol::net::TcpListener::try_from(listener).unwrap();
+        let task = smolscale::spawn(async move {
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut line = String::new();
+                smol::io::BufReader::new(conn.clone())
+                    .read_line(&mut line)
+                    .await
+                    .unwrap();
+                let request: nanorpc::JrpcRequest = serde_json::from_str(&line).unwrap();
+                let list = ExitList {
+                    all_exits: exits(),
+                    city_names: Default::default(),
+                };
+                let signed = Signed::new(list, "exit_list", &SigningKey::from_bytes(&[9; 32]));
+                let response = nanorpc::JrpcResponse {
+                    jsonrpc: "2.0".into(),
+                    result: Some(serde_json::to_value(signed).unwrap()),
+                    error: None,
+                    id: request.id,
+                };
+                let response = format!("{}\n", serde_json::to_string(&response).unwrap());
+                conn.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (addr, task)
+    }
+
+    #[test]
+    fn no_matching_exit_fails_the_start() {
+        smolscale::block_on(async {
+            let (broker, _broker) = fake_broker();
+            let opt = |args: &[&str]| {
+                let broker = broker.to_string();
+                let base = ["geph4-client", "--broker-addr", &broker];
+                ConnectOpt::from_iter_safe(base.iter().chain(args)).unwrap()
+            };
+            let err = pick_exit(&opt(&["--exit-country", "FR"]))
+                .await
+                .err()
+                .unwrap();
+            assert!(err.is::<NoMatchingExit>(), "{}", err);
+            let err = pick_exit(&opt(&["--exit-server", "192.0.2.9"]))
+                .await
+                .err()
+                .unwrap();
+            assert!(err.is::<NoMatchingExit>(), "{}", err);
+
+            // the selected exit, not geph5's placeholder, is what gets reported
+            let picked = pick_exit(&opt(&["--exit-server", "fra.de"])).await.unwrap();
+            assert_eq!(picked.exit.unwrap().city, "Frankfurt");
+            assert!(
+                matches!(picked.constraint, ExitConstraint::Direct(dest) if dest.starts_with("192.0.2.3:28080/"))
+            );
+            let picked = pick_exit(&opt(&["--exit-country", "CA"])).await.unwrap();
+            assert!(matches!(
+                picked.constraint,
+                ExitConstraint::Country(CountryCode::CAN)
+            ));
+        })
     }
 }
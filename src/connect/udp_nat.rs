@@ -0,0 +1,205 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use bytes::Bytes;
+use dashmap::{mapref::entry::Entry, DashMap};
+use pnet_packet::{
+    ip::IpNextHeaderProtocols,
+    ipv4::{Ipv4Packet, MutableIpv4Packet},
+    ipv6::{Ipv6Packet, MutableIpv6Packet},
+    udp::{MutableUdpPacket, UdpPacket},
+    MutablePacket, Packet,
+};
+use smol::channel::{Receiver, Sender};
+
+/// The source address of UDP datagrams that go through the tunnel's packet path without coming from the VPN. It never leaves the tunnel; it only lets their replies be told apart from VPN traffic.
+pub const NAT_ADDR: Ipv4Addr = Ipv4Addr::new(100, 64, 255, 254);
+
+/// Like [`NAT_ADDR`], but for IPv6 destinations. It is a unique local address, so it never leaves the tunnel either.
+pub const NAT_ADDR6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0x6765, 0x7068, 0, 0, 0, 0, 1);
+
+/// Replies that may queue up for one port before further ones are dropped.
+const PORT_QUEUE: usize = 1000;
+
+/// Lets UDP datagrams share the tunnel's packet path with the VPN, by sending them as IP packets from [`NAT_ADDR`] or [`NAT_ADDR6`] and routing replies back by destination port.
+#[derive(Default)]
+pub struct UdpNat {
+    ports: DashMap<u16, Sender<(SocketAddr, Bytes)>>,
+}
+
+impl UdpNat {
+    /// Reserves a free port, returning it along with the replies that arrive for it.
+    pub fn bind(&self) -> anyhow::Result<(u16, Receiver<(SocketAddr, Bytes)>)> {
+        for _ in 0..100 {
+            let port = fastrand::u16(1024..);
+            if let Entry::Vacant(entry) = self.ports.entry(port) {
+                let (send, recv) = smol::channel::bounded(PORT_QUEUE);
+                entry.insert(send);
+                return Ok((port, recv));
+            }
+        }
+        anyhow::bail!("ran out of NAT ports")
+    }
+
+    /// Releases a port reserved by [`UdpNat::bind`].
+    pub fn unbind(&self, port: u16) {
+        self.ports.remove(&port);
+    }
+
+    /// Hands a packet that came out of the tunnel to the port it is addressed to. Packets that are not for the NAT at all are given back, so that they can go to the VPN instead.
+    pub fn deliver(&self, pkt: Bytes) -> Option<Bytes> {
+        let (source, udp) = match pkt.first().map(|b| b >> 4) {
+            Some(4) => {
+                let Some(ip) = Ipv4Packet::new(&pkt) else {
+                    return Some(pkt);
+                };
+                if ip.get_destination() != NAT_ADDR
+                    || ip.get_next_level_protocol() != IpNextHeaderProtocols::Udp
+                {
+                    return Some(pkt);
+                }
+                (IpAddr::from(ip.get_source()), ip.payload().to_vec())
+            }
+            Some(6) => {
+                let Some(ip) = Ipv6Packet::new(&pkt) else {
+                    return Some(pkt);
+                };
+                if ip.get_destination() != NAT_ADDR6
+                    || ip.get_next_header() != IpNextHeaderProtocols::Udp
+                {
+                    return Some(pkt);
+                }
+                (IpAddr::from(ip.get_source()), ip.payload().to_vec())
+            }
+            _ => return Some(pkt),
+        };
+        if let Some(udp) = UdpPacket::new(&udp) {
+            let len = (udp.get_length() as usize)
+                .saturating_sub(8)
+                .min(udp.payload().len());
+            if let Some(port) = self.ports.get(&udp.get_destination()) {
+                let _ = port.try_send((
+                    SocketAddr::new(source, udp.get_source()),
+                    Bytes::copy_from_slice(&udp.payload()[..len]),
+                ));
+            }
+        }
+        None
+    }
+}
+
+/// Wraps a UDP datagram from the NAT port `port` in an IP packet of the destination's family, with checksums filled in.
+pub fn encapsulate(port: u16, dest: SocketAddr, payload: &[u8]) -> Bytes {
+    let udp_len = 8 + payload.len();
+    match dest.ip() {
+        IpAddr::V4(dest_ip) => {
+            let mut buf = vec![0u8; 20 + udp_len];
+            let mut ip = MutableIpv4Packet::new(&mut buf).expect("buffer fits the header");
+            ip.set_version(4);
+            ip.set_header_length(5);
+            ip.set_total_length((20 + udp_len) as u16);
+            ip.set_ttl(64);
+            ip.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+            ip.set_source(NAT_ADDR);
+            ip.set_destination(dest_ip);
+            {
+                let mut udp =
+                    MutableUdpPacket::new(ip.payload_mut()).expect("buffer fits the header");
+                fill_udp(&mut udp, port, dest.port(), payload);
+                let checksum =
+                    pnet_packet::udp::ipv4_checksum(&udp.to_immutable(), &NAT_ADDR, &dest_ip);
+                udp.set_checksum(checksum);
+            }
+            let checksum = pnet_packet::ipv4::checksum(&ip.to_immutable());
+            ip.set_checksum(checksum);
+            buf.into()
+        }
+        IpAddr::V6(dest_ip) => {
+            let mut buf = vec![0u8; 40 + udp_len];
+            let mut ip = MutableIpv6Packet::new(&mut buf).expect("buffer fits the header");
+            ip.set_version(6);
+            ip.set_payload_length(udp_len as u16);
+            ip.set_next_header(IpNextHeaderProtocols::Udp);
+            ip.set_hop_limit(64);
+            ip.set_source(NAT_ADDR6);
+            ip.set_destination(dest_ip);
+            let mut udp = MutableUdpPacket::new(ip.payload_mut()).expect("buffer fits the header");
+            fill_udp(&mut udp, port, dest.port(), payload);
+            let checksum =
+                pnet_packet::udp::ipv6_checksum(&udp.to_immutable(), &NAT_ADDR6, &dest_ip);
+            udp.set_checksum(checksum);
+            buf.into()
+        }
+    }
+}
+
+fn fill_udp(udp: &mut MutableUdpPacket, source: u16, dest: u16, payload: &[u8]) {
+    udp.set_source(source);
+    udp.set_destination(dest);
+    udp.set_length((8 + payload.len()) as u16);
+    udp.set_payload(payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Turns a packet from the NAT around, as the far side of the tunnel would when replying.
+    fn reply_to(pkt: &[u8], payload: &[u8]) -> Bytes {
+        match pkt[0] >> 4 {
+            4 => {
+                let ip = Ipv4Packet::new(pkt).unwrap();
+                let udp = UdpPacket::new(ip.payload()).unwrap();
+                let mut reply = encapsulate(
+                    udp.get_destination(),
+                    SocketAddr::new(NAT_ADDR.into(), udp.get_source()),
+                    payload,
+                )
+                .to_vec();
+                let mut reply_ip = MutableIpv4Packet::new(&mut reply).unwrap();
+                reply_ip.set_source(ip.get_destination());
+                reply.into()
+            }
+            _ => {
+                let ip = Ipv6Packet::new(pkt).unwrap();
+                let udp = UdpPacket::new(ip.payload()).unwrap();
+                let mut reply = encapsulate(
+                    udp.get_destination(),
+                    SocketAddr::new(NAT_ADDR6.into(), udp.get_source()),
+                    payload,
+                )
+                .to_vec();
+                let mut reply_ip = MutableIpv6Packet::new(&mut reply).unwrap();
+                reply_ip.set_source(ip.get_destination());
+                reply.into()
+            }
+        }
+    }
+
+    #[test]
+    fn encapsulate_and_deliver() {
+        let nat = UdpNat::default();
+        let (port, replies) = nat.bind().unwrap();
+        for dest in ["192.0.2.1:53", "[2001:db8::1]:53"] {
+            let dest: SocketAddr = dest.parse().unwrap();
+            let pkt = encapsulate(port, dest, b"question");
+            assert!(nat.deliver(reply_to(&pkt, b"answer")).is_none());
+            let (from, payload) = replies.try_recv().unwrap();
+            assert_eq!(from, dest);
+            assert_eq!(&payload[..], b"answer");
+        }
+    }
+
+    #[test]
+    fn other_packets_are_given_back() {
+        let nat = UdpNat::default();
+        let (port, replies) = nat.bind().unwrap();
+        // not addressed to the NAT, like VPN traffic
+        let pkt = encapsulate(port, "192.0.2.1:53".parse().unwrap(), b"question");
+        assert!(nat.deliver(pkt.clone()).is_some());
+        assert!(nat.deliver(Bytes::from_static(b"junk")).is_some());
+        // addressed to the NAT, but to a port nobody holds
+        nat.unbind(port);
+        assert!(nat.deliver(reply_to(&pkt, b"answer")).is_none());
+        assert!(replies.try_recv().is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use anyhow::Context;
 use smol::future::FutureExt;
 
-use super::ConnectContext;
+use super::{shaping::Direction, ConnectContext};
 use crate::config::VpnMode;
 
 #[cfg(target_os = "linux")]
@@ -15,7 +15,7 @@ use std::os::unix::prelude::{AsRawFd, FromRawFd};
 
 pub(super) async fn vpn_loop(ctx: ConnectContext) -> anyhow::Result<()> {
     #[cfg(any(target_os = "linux", target_os = "android"))]
-    if ctx.opt.vpn_mode == Some(VpnMode::InheritedFd) {
+    if ctx.opt().vpn_mode == Some(VpnMode::InheritedFd) {
         let fd_num: i32 = std::env::var("GEPH_VPN_FD")
             .ok()
             .and_then(|e| e.parse().ok())
@@ -24,13 +24,13 @@ pub(super) async fn vpn_loop(ctx: ConnectContext) -> anyhow::Result<()> {
     }
 
     #[cfg(target_os = "linux")]
-    if ctx.opt.vpn_mode == Some(VpnMode::TunNoRoute) {
+    if ctx.opt().vpn_mode == Some(VpnMode::TunNoRoute) {
         let device = configure_tun_device();
         return unsafe { fd_vpn_loop(ctx, device.as_raw_fd()).await };
     }
 
     #[cfg(target_os = "linux")]
-    if ctx.opt.vpn_mode == Some(VpnMode::TunRoute) {
+    if ctx.opt().vpn_mode == Some(VpnMode::TunRoute) {
         let device = configure_tun_device();
         return unsafe {
             fd_vpn_loop(ctx.clone(), device.as_raw_fd())
@@ -40,7 +40,7 @@ pub(super) async fn vpn_loop(ctx: ConnectContext) -> anyhow::Result<()> {
     }
 
     #[cfg(target_os = "windows")]
-    if ctx.opt.vpn_mode == Some(VpnMode::WinDivert) {
+    if ctx.opt().vpn_mode == Some(VpnMode::WinDivert) {
         return windows_routing::start_routing(ctx).await;
     }
 
@@ -78,6 +78,7 @@ async unsafe fn fd_vpn_loop(ctx: ConnectContext, fd_num: i32) -> anyhow::Result<
         let mut bts = vec![0; 65536];
         loop {
             let n = up_file.read(&mut bts).await?;
+            ctx.shape(Direction::Up, n).await;
 
             ctx.tunnel.send_vpn(&bts[..n]).await?;
         }
@@ -85,6 +86,7 @@ async unsafe fn fd_vpn_loop(ctx: ConnectContext, fd_num: i32) -> anyhow::Result<
     let dn_loop = async {
         loop {
             let bts = ctx.tunnel.recv_vpn().await?;
+            ctx.shape(Direction::Down, bts.len()).await;
             dn_file.write_all(&bts).await?;
         }
     };
@@ -2,13 +2,11 @@ use std::{process::Command, time::Duration};
 
 use crate::connect::ConnectContext;
 use anyhow::Context;
-use async_signal::{Signal, Signals};
 use clone_macro::clone;
 use dashmap::DashMap;
 use itertools::Itertools;
 use once_cell::sync::Lazy;
 
-use smol::stream::StreamExt;
 use tap::Tap;
 
 use std::net::IpAddr;
@@ -75,7 +73,7 @@ pub(super) async fn routing_loop(ctx: ConnectContext) -> anyhow::Result<()> {
     log::debug!("setting up VPN routing");
     std::env::set_var(
         "GEPH_DNS",
-        ctx.opt
+        ctx.opt()
             .dns_listen
             .tap_mut(|d| d.set_ip("127.0.0.1".parse().unwrap()))
             .to_string(),
@@ -95,17 +93,9 @@ pub(super) async fn routing_loop(ctx: ConnectContext) -> anyhow::Result<()> {
         libc::atexit(teardown_routing);
     }
 
+    // routing stays up until this loop is dropped, which happens when the daemon shuts down
     scopeguard::defer!(teardown_routing());
-
-    let mut signals = Signals::new([Signal::Term, Signal::Quit, Signal::Int])
-        .context("did not register signal handler properly")?;
-
-    if let Some(_signal) = signals.next().await {
-        teardown_routing();
-        std::process::exit(-1)
-    }
-
-    Ok(())
+    smol::future::pending().await
 }
 
 async fn whitelist_once(ctx: &ConnectContext) -> anyhow::Result<()> {
@@ -1,5 +1,6 @@
 use std::{
     sync::Arc,
+    thread::JoinHandle,
     time::{Duration, Instant, SystemTime},
 };
 
@@ -13,6 +14,7 @@ use structopt::StructOpt;
 use crate::config::CommonOpt;
 
 #[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct DebugPackOpt {
     #[structopt(flatten)]
     pub common: CommonOpt,
@@ -25,6 +27,7 @@ pub struct DebugPack {
     conn: Arc<Mutex<Connection>>,
     send_log: Sender<String>,
     send_timeseries: Sender<(String, f64)>,
+    writers: Mutex<Vec<JoinHandle<()>>>,
 }
 
 pub static START_TIME: Lazy<Instant> = Lazy::new(Instant::now);
@@ -67,7 +70,7 @@ impl DebugPack {
 
         let (send_log, recv_log) = smol::channel::bounded(10);
         let db_path2 = db_path.to_string();
-        std::thread::spawn(move || {
+        let log_writer = std::thread::spawn(move || {
             let conn = Connection::open(db_path2).unwrap();
             while let Ok(next) = recv_log.recv_blocking() {
                 if let Err(err) = conn.execute(
@@ -80,7 +83,7 @@ impl DebugPack {
         });
         let (send_timeseries, recv_timeseries) = smol::channel::bounded(10);
         let db_path2 = db_path.to_string();
-        std::thread::spawn(move || {
+        let timeseries_writer = std::thread::spawn(move || {
             let conn = Connection::open(db_path2).unwrap();
             while let Ok((key, value)) = recv_timeseries.recv_blocking() {
                 if let Err(err) = conn.execute(
@@ -96,9 +99,19 @@ impl DebugPack {
             conn: Arc::new(Mutex::new(conn)),
             send_log,
             send_timeseries,
+            writers: Mutex::new(vec![log_writer, timeseries_writer]),
         })
     }
 
+    /// Stops accepting new entries and blocks until everything already queued is written out.
+    pub fn flush_and_close(&self) {
+        self.send_log.close();
+        self.send_timeseries.close();
+        for writer in self.writers.lock().drain(..) {
+            let _ = writer.join();
+        }
+    }
+
     pub fn add_logline(&self, logline: &str) {
         let _ = self.send_log.try_send(logline.into());
     }
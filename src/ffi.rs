@@ -0,0 +1,267 @@
+//! The C ABI declared in `geph4client.h`.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_void, CStr, CString},
+    ptr,
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use smol::Task;
+
+use crate::{config::Opt, connect::ConnectContext, BasicStats, ConnectDaemon, SubsystemHealth};
+
+/// The daemon started by `geph4_start`, if any. The lock is never held while blocking, so that no call waits on another one's RPCs.
+static DAEMON: Lazy<Mutex<Option<DaemonState>>> = Lazy::new(Default::default);
+
+enum DaemonState {
+    /// A daemon started by `geph4_start`, its context for answering queries, and the task that shuts it down when the stats RPC's `kill` method asks for it.
+    Running {
+        daemon: Box<ConnectDaemon>,
+        ctx: ConnectContext,
+        kill_watcher: Task<()>,
+    },
+    /// The task shutting the daemon down after the `kill` method was called, which may already have finished. Starting or stopping the daemon waits for it, so that a new daemon never races the old one for its ports.
+    Killed(Task<()>),
+}
+
+/// Waits for a shutdown started by the `kill` method, if there is one, and clears it.
+fn wait_killed() -> bool {
+    let mut state = DAEMON.lock();
+    match state.take() {
+        Some(DaemonState::Killed(shutdown)) => {
+            drop(state);
+            smolscale::block_on(shutdown);
+            true
+        }
+        other => {
+            *state = other;
+            false
+        }
+    }
+}
+
+/// The context of the running daemon, if there is one.
+fn running_context() -> Option<ConnectContext> {
+    match DAEMON.lock().as_ref() {
+        Some(DaemonState::Running { ctx, .. }) => Some(ctx.clone()),
+        _ => None,
+    }
+}
+
+/// The task feeding log lines to the callback registered by `geph4_set_log_callback`, if any.
+static LOG_FORWARDER: Lazy<Mutex<Option<Task<()>>>> = Lazy::new(Default::default);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(err: impl std::fmt::Display) {
+    let msg = CString::new(err.to_string().replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+}
+
+/// Reads a C string argument, failing on NULL or invalid UTF-8.
+unsafe fn read_cstr<'a>(s: *const c_char) -> anyhow::Result<&'a str> {
+    anyhow::ensure!(!s.is_null(), "got a NULL string");
+    Ok(CStr::from_ptr(s).to_str()?)
+}
+
+#[derive(Serialize)]
+struct StatsReport {
+    connected: bool,
+    basic: Option<BasicStats>,
+    subsystems: std::collections::BTreeMap<String, SubsystemHealth>,
+}
+
+/// Runs any subcommand from its JSON-serialized `Opt`, blocking until it finishes, just like the command line would.
+///
+/// # Safety
+/// `opt` must be NULL or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn call_from_c(opt: *const c_char) {
+    let result = read_cstr(opt).and_then(|json| {
+        crate::logs::init_logging();
+        let opt = Opt::from_json(json)?;
+        smolscale::block_on(crate::run(opt, || Opt::from_json(json)))
+    });
+    if let Err(err) = result {
+        log::error!("call_from_c failed: {:?}", err);
+        set_last_error(format!("{:#}", err));
+    }
+}
+
+/// Starts the connect daemon from a JSON-serialized `Opt` of the `Connect` variant. Returns 0 on success and -1 on failure.
+///
+/// # Safety
+/// `opt` must be NULL or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn geph4_start(opt: *const c_char) -> i32 {
+    let result = read_cstr(opt).and_then(|json| {
+        crate::logs::init_logging();
+        let opt = match Opt::from_json(json)? {
+            Opt::Connect(opt) => opt,
+            _ => anyhow::bail!("geph4_start needs options for Connect"),
+        };
+        wait_killed();
+        anyhow::ensure!(DAEMON.lock().is_none(), "the daemon is already running");
+        // the lock is not held while starting, so the other calls never wait on the broker
+        let started = smolscale::block_on(ConnectDaemon::start(opt))?;
+        let mut daemon = DAEMON.lock();
+        if daemon.is_some() {
+            drop(daemon);
+            smolscale::block_on(started.shutdown());
+            anyhow::bail!("the daemon is already running");
+        }
+        // only the kill RPC is watched for, since signals belong to the host process
+        let kill_requested = started.kill_requested();
+        let kill_watcher = smolscale::spawn(async move {
+            kill_requested.await;
+            let daemon = {
+                let mut state = DAEMON.lock();
+                match state.take() {
+                    Some(DaemonState::Running {
+                        daemon,
+                        kill_watcher,
+                        ..
+                    }) => {
+                        // this very task, which is kept until the shutdown is over for others to wait on
+                        *state = Some(DaemonState::Killed(kill_watcher));
+                        daemon
+                    }
+                    other => {
+                        *state = other;
+                        return;
+                    }
+                }
+            };
+            daemon.shutdown().await;
+        });
+        *daemon = Some(DaemonState::Running {
+            ctx: started.context(),
+            daemon: Box::new(started),
+            kill_watcher,
+        });
+        Ok(())
+    });
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(format!("{:#}", err));
+            -1
+        }
+    }
+}
+
+/// Shuts the daemon down gracefully, blocking until it has stopped. Returns 0 on success and -1 if it was not running. If the stats RPC's `kill` method is already stopping it, this waits for that to finish and returns -1.
+#[no_mangle]
+pub extern "C" fn geph4_stop() -> i32 {
+    if wait_killed() {
+        set_last_error("the daemon was stopped by the kill method");
+        return -1;
+    }
+    let running = DAEMON.lock().take();
+    match running {
+        Some(DaemonState::Running {
+            daemon,
+            kill_watcher,
+            ..
+        }) => {
+            drop(kill_watcher);
+            smolscale::block_on(daemon.shutdown());
+            0
+        }
+        Some(DaemonState::Killed(shutdown)) => {
+            // the kill method was called in the meantime
+            smolscale::block_on(shutdown);
+            set_last_error("the daemon was stopped by the kill method");
+            -1
+        }
+        None => {
+            set_last_error("the daemon is not running");
+            -1
+        }
+    }
+}
+
+/// Returns 1 if the tunnel is connected, 0 if it is still connecting, and -1 if the daemon is not running.
+#[no_mangle]
+pub extern "C" fn geph4_is_connected() -> i32 {
+    match running_context() {
+        Some(ctx) => smol::block_on(ctx.status()).connected() as i32,
+        None => {
+            set_last_error("the daemon is not running");
+            -1
+        }
+    }
+}
+
+/// Returns the daemon's statistics as a JSON object with `connected`, `basic` and `subsystems` fields, or NULL if the daemon is not running. The string must be freed with `geph4_free_string`.
+#[no_mangle]
+pub extern "C" fn geph4_stats_json() -> *mut c_char {
+    let Some(ctx) = running_context() else {
+        set_last_error("the daemon is not running");
+        return ptr::null_mut();
+    };
+    let report = StatsReport {
+        connected: smol::block_on(ctx.status()).connected(),
+        basic: ctx.stats(),
+        subsystems: ctx.subsystem_health(),
+    };
+    let json = serde_json::to_string(&report).expect("stats always serialize");
+    CString::new(json).unwrap().into_raw()
+}
+
+/// Frees a string returned by this library.
+///
+/// # Safety
+/// `s` must be NULL or a string returned by this library that has not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn geph4_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Returns a description of the last error on this thread, or NULL if there was none. The string stays valid until the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn geph4_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map(|msg| msg.as_ptr())
+            .unwrap_or(ptr::null())
+    })
+}
+
+/// A log callback and the opaque pointer it is called with.
+struct LogCallback {
+    callback: extern "C" fn(*const c_char, *mut c_void),
+    userdata: *mut c_void,
+}
+
+// the C side promises that the callback may be called from any thread
+unsafe impl Send for LogCallback {}
+
+/// Registers a callback that receives every log line, from a background thread. Passing NULL removes the callback.
+#[no_mangle]
+pub extern "C" fn geph4_set_log_callback(
+    callback: Option<extern "C" fn(*const c_char, *mut c_void)>,
+    userdata: *mut c_void,
+) {
+    let mut forwarder = LOG_FORWARDER.lock();
+    *forwarder = callback.map(|callback| {
+        crate::logs::init_logging();
+        let logs = crate::logs::subscribe_logs();
+        let cb = LogCallback { callback, userdata };
+        smolscale::spawn(async move {
+            let cb = cb;
+            while let Ok(line) = logs.recv().await {
+                let line = CString::new(line.replace('\0', " ")).unwrap();
+                (cb.callback)(line.as_ptr(), cb.userdata);
+            }
+        })
+    });
+}
@@ -1,32 +1,53 @@
 use std::{
-    sync::atomic::{AtomicUsize, Ordering},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
 use anyhow::Context;
 use async_trait::async_trait;
 use backoff::{backoff::Backoff, ExponentialBackoffBuilder};
-use geph4_protocol::binder::client::E2eeHttpTransport;
+use geph4_protocol::binder::{
+    client::E2eeHttpTransport,
+    protocol::{box_decrypt, box_encrypt},
+};
+use geph5_client::BrokerSource;
+use http_types::Method;
 
+use futures_util::{AsyncBufReadExt, AsyncWriteExt, FutureExt};
 use itertools::Itertools;
-use nanorpc::{DynRpcTransport, RpcTransport};
+use nanorpc::{DynRpcTransport, JrpcError, JrpcResponse, RpcTransport};
 use once_cell::sync::Lazy;
+use smol::Task;
 use smol_timeout::TimeoutExt;
+use smolscale::reaper::TaskReaper;
+
+use crate::upstream_proxy::UpstreamProxy;
 
-/// Parses a list of front/host pairs and produces a DynRpcTransport.
+/// Parses a list of front/host pairs and produces a DynRpcTransport. When a proxy is given, every request goes through it.
 pub fn parse_fronts(
     binder_lpk: [u8; 32],
     fronts: impl IntoIterator<Item = (String, String)>,
+    proxy: Option<UpstreamProxy>,
 ) -> DynRpcTransport {
     // make a list of the different alternatives, then select between them at random while increasing the timeout every time
     let alternatives = fronts
         .into_iter()
-        .map(|(endpoint, real_host)| {
-            DynRpcTransport::new(E2eeHttpTransport::new(
+        .map(|(endpoint, real_host)| match &proxy {
+            Some(proxy) => DynRpcTransport::new(ProxiedE2eeTransport {
+                binder_lpk: x25519_dalek::PublicKey::from(binder_lpk),
+                endpoint,
+                host: real_host,
+                proxy: proxy.clone(),
+            }),
+            None => DynRpcTransport::new(E2eeHttpTransport::new(
                 binder_lpk,
                 endpoint,
                 vec![("host".to_string(), real_host)],
-            ))
+            )),
         })
         .collect_vec();
     let unified = MultiRpcTransport(alternatives);
@@ -77,3 +98,168 @@ impl RpcTransport for MultiRpcTransport {
         }
     }
 }
+
+/// Like [`E2eeHttpTransport`], but going through a proxy, which `E2eeHttpTransport` has no way of doing.
+struct ProxiedE2eeTransport {
+    binder_lpk: x25519_dalek::PublicKey,
+    endpoint: String,
+    host: String,
+    proxy: UpstreamProxy,
+}
+
+#[async_trait]
+impl RpcTransport for ProxiedE2eeTransport {
+    type Error = anyhow::Error;
+
+    async fn call_raw(
+        &self,
+        req: nanorpc::JrpcRequest,
+    ) -> Result<nanorpc::JrpcResponse, Self::Error> {
+        let eph_sk = x25519_dalek::StaticSecret::from(rand::random::<[u8; 32]>());
+        let encrypted_req =
+            box_encrypt(&serde_json::to_vec(&req)?, eph_sk.clone(), self.binder_lpk);
+        let encrypted_resp = self
+            .proxy
+            .request(
+                Method::Post,
+                &self.endpoint,
+                Some(&self.host.to_ascii_lowercase()),
+                encrypted_req.to_vec(),
+            )
+            .await?;
+        let (resp, _) = box_decrypt(&encrypted_resp, eph_sk)?;
+        Ok(serde_json::from_slice(&resp)?)
+    }
+}
+
+/// Reaches the geph5 broker through a proxy, the way [`BrokerSource::rpc_transport`] reaches it directly.
+pub fn proxied_broker_transport(source: &BrokerSource, proxy: &UpstreamProxy) -> DynRpcTransport {
+    match source {
+        BrokerSource::Race(sources) => DynRpcTransport::new(RaceRpcTransport(
+            sources
+                .iter()
+                .map(|source| proxied_broker_transport(source, proxy))
+                .collect(),
+        )),
+        source => DynRpcTransport::new(ProxiedBrokerTransport {
+            source: source.clone(),
+            proxy: proxy.clone(),
+        }),
+    }
+}
+
+struct ProxiedBrokerTransport {
+    source: BrokerSource,
+    proxy: UpstreamProxy,
+}
+
+#[async_trait]
+impl RpcTransport for ProxiedBrokerTransport {
+    type Error = anyhow::Error;
+
+    async fn call_raw(
+        &self,
+        req: nanorpc::JrpcRequest,
+    ) -> Result<nanorpc::JrpcResponse, Self::Error> {
+        let body = serde_json::to_vec(&req)?;
+        let resp = match &self.source {
+            BrokerSource::Direct(url) => self.proxy.request(Method::Post, url, None, body).await?,
+            BrokerSource::Fronted { front, host } => {
+                self.proxy
+                    .request(Method::Post, front, Some(host), body)
+                    .await?
+            }
+            BrokerSource::DirectTcp(addr) => {
+                // the same newline-delimited JSON that geph5 speaks to such brokers
+                let mut conn = self.proxy.connect(&addr.to_string()).await?;
+                conn.write_all(&body).await?;
+                conn.write_all(b"\n").await?;
+                let mut line = String::new();
+                smol::io::BufReader::new(conn).read_line(&mut line).await?;
+                line.into_bytes()
+            }
+            _ => anyhow::bail!("this kind of broker cannot be reached through a proxy"),
+        };
+        Ok(serde_json::from_slice(&resp)?)
+    }
+}
+
+/// Tries every transport at once, taking the first answer.
+struct RaceRpcTransport(Vec<DynRpcTransport>);
+
+#[async_trait]
+impl RpcTransport for RaceRpcTransport {
+    type Error = anyhow::Error;
+
+    async fn call_raw(
+        &self,
+        req: nanorpc::JrpcRequest,
+    ) -> Result<nanorpc::JrpcResponse, Self::Error> {
+        let calls = self
+            .0
+            .iter()
+            .map(|transport| transport.call_raw(req.clone()))
+            .collect_vec();
+        let (resp, _) = futures_util::future::select_ok(calls).await?;
+        Ok(resp)
+    }
+}
+
+/// Serves broker requests on a local port, passing them on through `transport`. geph5 cannot use a proxy, so pointing it at such a relay is how its broker requests go through one. The relay, and every connection to it, stops when the returned task is dropped.
+pub fn relay_broker(transport: DynRpcTransport) -> anyhow::Result<(SocketAddr, Task<()>)> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let listener = smol::net::TcpListener::try_from(listener)?;
+    let transport = Arc::new(transport);
+    let task = smolscale::spawn(async move {
+        let conns = TaskReaper::new();
+        loop {
+            match listener.accept().await {
+                Ok((conn, _)) => conns.attach(smolscale::spawn(
+                    relay_broker_conn(conn, transport.clone()).map(drop),
+                )),
+                Err(err) => {
+                    log::warn!("broker relay stopped: {:?}", err);
+                    return;
+                }
+            }
+        }
+    });
+    Ok((addr, task))
+}
+
+async fn relay_broker_conn(
+    mut conn: smol::net::TcpStream,
+    transport: Arc<DynRpcTransport>,
+) -> anyhow::Result<()> {
+    let mut reader = smol::io::BufReader::new(conn.clone());
+    let mut line = String::new();
+    while reader.read_line(&mut line).await? > 0 {
+        let req: nanorpc::JrpcRequest = serde_json::from_str(&line)?;
+        line.clear();
+        let id = req.id.clone();
+        let resp = match transport.call_raw(req).await {
+            Ok(resp) => resp,
+            Err(err) => {
+                log::warn!(
+                    "cannot reach the broker through the outbound proxy: {:?}",
+                    err
+                );
+                // pass the reason on, so that geph5 does not just see the connection drop
+                JrpcResponse {
+                    jsonrpc: "2.0".into(),
+                    result: None,
+                    error: Some(JrpcError {
+                        code: -32000,
+                        message: format!("outbound proxy: {:#}", err),
+                        data: serde_json::Value::Null,
+                    }),
+                    id,
+                }
+            }
+        };
+        conn.write_all(format!("{}\n", serde_json::to_string(&resp)?).as_bytes())
+            .await?;
+    }
+    Ok(())
+}
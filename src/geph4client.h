@@ -3,4 +3,45 @@
 #include <stdint.h>
 #include <stdlib.h>
 
-void call_from_c(const char *opt);
\ No newline at end of file
+/*
+ * Options are passed as JSON in the same format as a --config file, like
+ * {"Connect": {"exclude_prc": true, "auth": {...}}}. Options missing from the
+ * JSON take their usual defaults.
+ *
+ * Functions returning int return -1 on failure; geph4_last_error then
+ * describes what went wrong.
+ */
+
+/* Runs any subcommand from its JSON options, blocking until it finishes. */
+void call_from_c(const char *opt);
+
+/* Starts the connect daemon from JSON options for Connect. Only one daemon
+ * can run at a time. Returns 0 on success. The daemon stops by itself when
+ * the kill method of its stats RPC is called; it never handles signals. */
+int geph4_start(const char *opt);
+
+/* Shuts the daemon down gracefully, blocking until it has stopped. Returns 0
+ * on success. If the kill method is already stopping the daemon, waits for
+ * that to finish and returns -1. geph4_start likewise waits for it. */
+int geph4_stop(void);
+
+/* Returns 1 if the tunnel is connected, 0 if it is still connecting, and -1
+ * if the daemon is not running. */
+int geph4_is_connected(void);
+
+/* Returns the daemon's statistics as a JSON object with "connected", "basic"
+ * and "subsystems" fields, or NULL if the daemon is not running. Free the
+ * result with geph4_free_string. */
+char *geph4_stats_json(void);
+
+/* Frees a string returned by this library. */
+void geph4_free_string(char *s);
+
+/* Returns a description of the last error on the calling thread, or NULL.
+ * The string stays valid until the next failing call on that thread. */
+const char *geph4_last_error(void);
+
+/* Registers a callback that receives every log line, from a background
+ * thread. Passing NULL removes the callback. */
+typedef void (*geph4_log_callback)(const char *line, void *userdata);
+void geph4_set_log_callback(geph4_log_callback callback, void *userdata);
@@ -1,30 +1,76 @@
-use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Once,
+};
 
-pub struct LogBuffer {
-    logs: VecDeque<char>,
-    mem_limit: usize, // in # of characters
+use colored::Colorize;
+use once_cell::sync::Lazy;
+use pad::{Alignment, PadStr};
+use parking_lot::Mutex;
+use smol::channel::{Receiver, Sender};
+
+static LOG_SUBSCRIBERS: Lazy<Mutex<Vec<Sender<String>>>> = Lazy::new(Default::default);
+
+/// Returns a stream of the lines logged from now on, without ANSI colors. A subscriber that falls more than 1000 lines behind misses lines rather than slowing down logging.
+///
+/// Only lines that go through the logger installed by [`init_logging`] show up here.
+pub fn subscribe_logs() -> Receiver<String> {
+    let (send, recv) = smol::channel::bounded(1000);
+    LOG_SUBSCRIBERS.lock().push(send);
+    recv
 }
 
-impl LogBuffer {
-    pub fn new(mem_limit: usize) -> Self {
-        Self {
-            logs: VecDeque::new(),
-            mem_limit,
-        }
+fn publish_line(line: String) {
+    let mut subscribers = LOG_SUBSCRIBERS.lock();
+    subscribers.retain(|sub| !sub.is_closed());
+    for sub in subscribers.iter() {
+        let _ = sub.try_send(line.clone());
     }
+}
 
-    pub fn add_line(&mut self, line: &str) {
-        for c in line.chars() {
-            self.logs.push_back(c);
-        }
-        self.logs.push_back('\n');
+static LONGEST_LINE_EVER: AtomicUsize = AtomicUsize::new(0);
 
-        while self.logs.len() > self.mem_limit {
-            self.logs.pop_front();
-        }
-    }
+static INIT_LOGGING: Once = Once::new();
+
+/// Installs geph4-client's logger, which prints colored lines to stderr and feeds [`subscribe_logs`]. Does nothing if a logger is already installed.
+pub fn init_logging() {
+    INIT_LOGGING.call_once(install_logger)
+}
 
-    pub fn get_logs(&self) -> String {
-        self.logs.clone().into_iter().collect()
+fn install_logger() {
+    if let Err(e) =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(
+            "geph4client=debug,geph4_protocol=debug,melprot=debug,warn,geph5=debug",
+        ))
+        .format_timestamp_millis()
+        .format(move |buf, record| {
+            let preamble = format!(
+                "[{} {}]:",
+                record.module_path().unwrap_or("none").dimmed(),
+                match record.level() {
+                    log::Level::Error => "ERRO".red(),
+                    log::Level::Warn => "WARN".bright_yellow(),
+                    log::Level::Info => "INFO".bright_green(),
+                    log::Level::Debug => "DEBG".bright_blue(),
+                    log::Level::Trace => "TRAC".bright_black(),
+                },
+            );
+            let len = strip_ansi_escapes::strip(&preamble).unwrap().len();
+            let longest = LONGEST_LINE_EVER.fetch_max(len, Ordering::SeqCst);
+            let preamble = ""
+                .pad_to_width_with_alignment(longest.saturating_sub(len), Alignment::Right)
+                + &preamble;
+            let line = format!("{} {}", preamble, record.args());
+            writeln!(buf, "{}", line).unwrap();
+            publish_line(
+                String::from_utf8_lossy(&strip_ansi_escapes::strip(line).unwrap()).to_string(),
+            );
+            Ok(())
+        })
+        .format_target(false)
+        .try_init()
+    {
+        log::debug!("{}", e);
     }
 }
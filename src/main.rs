@@ -1,5 +1,5 @@
 use binary_search::Direction;
-use geph4client::dispatch;
+use geph4client::{dispatch, ConfigInvalid};
 
 fn main() -> anyhow::Result<()> {
     smolscale::permanently_single_threaded();
@@ -13,5 +13,9 @@ fn main() -> anyhow::Result<()> {
     let _ = rlimit::utils::increase_nofile_limit(largest_low);
     log::info!("** set fd limit to {} **", largest_low);
 
-    dispatch()
+    match dispatch() {
+        // check-config has already printed the problems
+        Err(err) if err.is::<ConfigInvalid>() => std::process::exit(1),
+        result => result,
+    }
 }
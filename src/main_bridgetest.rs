@@ -7,6 +7,7 @@ use structopt::StructOpt;
 use crate::config::{AuthOpt, CommonOpt};
 
 #[derive(Debug, StructOpt, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct BridgeTestOpt {
     #[structopt(flatten)]
     pub common: CommonOpt,
@@ -1,13 +1,13 @@
 use crate::socks2http::address::{host_addr, Address};
-use crate::socks2http::http_client;
-use crate::socks2http::socks5;
-use futures_util::FutureExt;
+use crate::socks2http::{ClientVerdict, Login, OpenError, Upstreams};
+use futures_util::{future::BoxFuture, FutureExt};
 use http::{
     uri::{Authority, Scheme, Uri},
     Method,
 };
 use http::{HeaderMap, HeaderValue, Version};
 use hyper::{
+    client::connect::{Connected, Connection},
     server::conn::AddrStream,
     service::{make_service_fn, service_fn},
     Body, Request, Response,
@@ -15,14 +15,37 @@ use hyper::{
 use log::trace;
 use std::convert::Infallible;
 use std::net::SocketAddr;
-pub async fn run(listen_addr: SocketAddr, proxy_address: SocketAddr) -> std::io::Result<()> {
-    let shared_server: SharedProxyServer = ProxyServer::new_shared(proxy_address);
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{self, Poll};
+pub async fn run<U: Upstreams, G: Send + Sync + 'static>(
+    listen_addr: SocketAddr,
+    upstreams: Arc<U>,
+    verdict: impl Fn(SocketAddr) -> ClientVerdict<G>,
+) -> std::io::Result<()> {
     let make_service = make_service_fn(|socket: &AddrStream| {
         let client_addr = socket.remote_addr();
-        let cloned_server = shared_server.clone();
+        let verdict = verdict(client_addr);
+        let upstreams = upstreams.clone();
         async move {
-            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                server_dispatch(req, client_addr, cloned_server.clone())
+            // the service lives as long as the connection, and so does the guard it holds
+            let proxy = match verdict {
+                ClientVerdict::Serve(guard) => Some(ProxyConnection::new_shared(
+                    upstreams,
+                    client_addr,
+                    Arc::new(guard),
+                )),
+                ClientVerdict::Forbid => None,
+                ClientVerdict::Close => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionRefused,
+                        "client refused",
+                    ))
+                }
+            };
+            Ok(service_fn(move |req: Request<Body>| match &proxy {
+                Some(proxy) => server_dispatch(req, proxy.clone()).left_future(),
+                None => std::future::ready(Ok(make_forbidden())).right_future(),
             }))
         }
     });
@@ -36,12 +59,52 @@ pub async fn run(listen_addr: SocketAddr, proxy_address: SocketAddr) -> std::io:
     Ok(())
 }
 
+/// Serves HTTP proxy requests on one connection that was accepted elsewhere, such as on the mixed port.
+pub async fn serve_connection<S, U: Upstreams>(
+    stream: S,
+    client_addr: SocketAddr,
+    upstreams: Arc<U>,
+    guard: ConnectionGuard,
+) -> hyper::Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let proxy = ProxyConnection::new_shared(upstreams, client_addr, guard);
+    hyper::server::conn::Http::new()
+        .http1_only(true)
+        .serve_connection(
+            stream,
+            service_fn(move |req: Request<Body>| server_dispatch(req, proxy.clone())),
+        )
+        .with_upgrades()
+        .await
+}
+
+/// Serves a connection from a client that may not use the proxy, answering each request with 403 Forbidden.
+pub async fn serve_forbidden<S>(stream: S) -> hyper::Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    hyper::server::conn::Http::new()
+        .http1_only(true)
+        .serve_connection(
+            stream,
+            service_fn(|_: Request<Body>| {
+                std::future::ready(Ok::<_, Infallible>(make_forbidden()))
+            }),
+        )
+        .await
+}
+
 use std::str::FromStr;
-async fn server_dispatch(
+/// Held for as long as a client's connection, or anything relayed for it, is still in use.
+pub type ConnectionGuard = Arc<dyn Send + Sync>;
+
+async fn server_dispatch<U: Upstreams>(
     mut req: Request<Body>,
-    client_addr: SocketAddr,
-    proxy_server: SharedProxyServer,
+    proxy: Arc<ProxyConnection<U>>,
 ) -> std::io::Result<Response<Body>> {
+    let client_addr = proxy.client_addr;
     let host = match host_addr(req.uri()) {
         None => {
             if req.uri().authority().is_some() {
@@ -123,31 +186,41 @@ async fn server_dispatch(
         }
         Some(h) => h,
     };
+    let login = proxy_login(req.headers());
+    let Some(user) = proxy.upstreams.login(login.as_ref()) else {
+        return Ok(make_auth_required());
+    };
     if Method::CONNECT == req.method() {
-        let addr: SocketAddr = proxy_server.addr;
-        let stream = socks5::connect(&host, &addr).await?;
-        trace!(
-            "CONNECT relay connected {} <-> {} ({})",
-            client_addr,
-            addr,
-            host
-        );
+        let conn = match proxy.upstreams.open(&host).await {
+            Ok(conn) => conn,
+            Err(err) => {
+                trace!("CONNECT {} ({}) failed, error: {}", client_addr, host, err);
+                return Ok(make_open_failed(&err));
+            }
+        };
+        trace!("CONNECT relay connected {} <-> {}", client_addr, host);
         tokio::spawn(async move {
             match hyper::upgrade::on(req).await {
                 Ok(upgraded) => {
                     trace!(
-                        "CONNECT tunnel upgrade success, {} <-> {} ({})",
+                        "CONNECT tunnel upgrade success, {} <-> {}",
                         client_addr,
-                        addr,
                         host
                     );
-                    establish_connect_tunnel(upgraded, stream, &addr, client_addr, host).await
+                    // the connection is done with once it is upgraded, so the tunnel holds the guard from here on
+                    proxy.upstreams.relay(
+                        async_compat::Compat::new(upgraded),
+                        client_addr,
+                        &host,
+                        conn,
+                        user,
+                        proxy.guard.clone(),
+                    )
                 }
                 Err(e) => {
                     trace!(
-                        "Failed to upgrade TCP tunnel {} <-> {} ({}), error: {}",
+                        "Failed to upgrade TCP tunnel {} <-> {}, error: {}",
                         client_addr,
-                        addr,
                         host,
                         e
                     );
@@ -162,20 +235,21 @@ async fn server_dispatch(
         let conn_keep_alive = check_keep_alive(req.version(), req.headers(), true);
         clear_hop_headers(req.headers_mut());
         set_conn_keep_alive(req.version(), req.headers_mut(), conn_keep_alive);
-        let mut res: Response<Body> = match proxy_server.client.request(req).await {
+        let client = proxy.client_for(login, user);
+        let mut res: Response<Body> = match client.request(req).await {
             Ok(res) => res,
             Err(err) => {
                 trace!(
-                    "HTTP {} {} <-> {} ({}) relay failed, error: {}",
+                    "HTTP {} {} <-> {} relay failed, error: {}",
                     method,
                     client_addr,
-                    "127.0.0.1:1080",
                     host,
                     err
                 );
-                let mut resp = Response::new(Body::from(format!("Relay failed to {}", host)));
-                *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                return Ok(resp);
+                return Ok(match open_error_of(&err) {
+                    Some(err) => make_open_failed(err),
+                    None => make_relay_failed(&host),
+                });
             }
         };
         let res_keep_alive =
@@ -185,91 +259,69 @@ async fn server_dispatch(
         Ok(res)
     }
 }
-use futures_util::future::{self, Either};
-use hyper::{upgrade::Upgraded, StatusCode};
-use std::io::ErrorKind;
-use tokio::net::TcpStream;
-async fn establish_connect_tunnel(
-    upgraded: Upgraded,
-    mut stream: TcpStream,
-    svr_addr: &SocketAddr,
-    client_addr: SocketAddr,
-    addr: Address,
-) {
-    use tokio::io::{copy, split};
-
-    let (mut r, mut w) = split(upgraded);
-    let (mut svr_r, mut svr_w) = stream.split();
+use hyper::StatusCode;
 
-    let rhalf = copy(&mut r, &mut svr_w);
-    let whalf = copy(&mut svr_r, &mut w);
+/// Reads the `Proxy-Authorization: Basic` login, if there is a well-formed one.
+fn proxy_login(headers: &HeaderMap<HeaderValue>) -> Option<Login> {
+    use base64::Engine;
+    let value = headers.get("Proxy-Authorization")?.to_str().ok()?;
+    let (scheme, credentials) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(credentials.trim())
+        .ok()?;
+    let (username, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+    Some(Login {
+        username: username.to_owned(),
+        password: password.to_owned(),
+    })
+}
 
-    trace!(
-        "CONNECT relay established {} <-> {} ({})",
-        client_addr,
-        svr_addr,
-        addr
+fn make_auth_required() -> Response<Body> {
+    let mut resp = Response::new(Body::from("Proxy login required"));
+    *resp.status_mut() = StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+    resp.headers_mut().insert(
+        "Proxy-Authenticate",
+        HeaderValue::from_static("Basic realm=\"Geph\""),
     );
+    resp
+}
 
-    match future::select(rhalf.boxed(), whalf.boxed()).await {
-        Either::Left((Ok(..), _)) => trace!(
-            "CONNECT relay {} -> {} ({}) closed",
-            client_addr,
-            svr_addr,
-            addr
-        ),
-        Either::Left((Err(err), _)) => {
-            if let ErrorKind::TimedOut = err.kind() {
-                trace!(
-                    "CONNECT relay {} -> {} ({}) closed with error {}",
-                    client_addr,
-                    svr_addr,
-                    addr,
-                    err,
-                );
-            } else {
-                trace!(
-                    "CONNECT relay {} -> {} ({}) closed with error {}",
-                    client_addr,
-                    svr_addr,
-                    addr,
-                    err,
-                );
-            }
-        }
-        Either::Right((Ok(..), _)) => trace!(
-            "CONNECT relay {} <- {} ({}) closed",
-            client_addr,
-            svr_addr,
-            addr
-        ),
-        Either::Right((Err(err), _)) => {
-            if let ErrorKind::TimedOut = err.kind() {
-                trace!(
-                    "CONNECT relay {} <- {} ({}) closed with error {}",
-                    client_addr,
-                    svr_addr,
-                    addr,
-                    err,
-                );
-            } else {
-                trace!(
-                    "CONNECT relay {} <- {} ({}) closed with error {}",
-                    client_addr,
-                    svr_addr,
-                    addr,
-                    err,
-                );
-            }
+/// The error that opening an upstream connection failed with, wherever it is in the chain of `err`.
+fn open_error_of<'a>(err: &'a (dyn std::error::Error + 'static)) -> Option<&'a OpenError> {
+    let mut err = Some(err);
+    while let Some(inner) = err {
+        if let Some(open) = inner.downcast_ref::<OpenError>() {
+            return Some(open);
         }
+        err = inner.source();
     }
+    None
+}
 
-    trace!(
-        "CONNECT relay {} <-> {} ({}) closed",
-        client_addr,
-        svr_addr,
-        addr
-    );
+fn make_open_failed(err: &OpenError) -> Response<Body> {
+    let mut resp = Response::new(Body::from(err.message.clone()));
+    *resp.status_mut() = err.status;
+    resp
+}
+
+fn make_relay_failed(host: &Address) -> Response<Body> {
+    let mut resp = Response::new(Body::from(format!("Relay failed to {}", host)));
+    *resp.status_mut() = StatusCode::BAD_GATEWAY;
+    resp
+}
+
+fn make_forbidden() -> Response<Body> {
+    let mut resp = Response::new(Body::from(
+        "This proxy does not accept connections from your address",
+    ));
+    *resp.status_mut() = StatusCode::FORBIDDEN;
+    // nothing else the client sends will be let through either
+    resp.headers_mut()
+        .insert("Connection", HeaderValue::from_static("close"));
+    resp
 }
 
 fn make_bad_request() -> Response<Body> {
@@ -443,22 +495,133 @@ fn set_conn_keep_alive(version: Version, headers: &mut HeaderMap<HeaderValue>, k
         _ => unimplemented!("HTTP Proxy only supports 1.0 and 1.1"),
     }
 }
-#[derive(Clone)]
-pub struct ProxyServer {
-    client: http_client::SocksClient,
-    addr: SocketAddr,
+/// What the requests on one client connection share.
+struct ProxyConnection<U: Upstreams> {
+    upstreams: Arc<U>,
+    client_addr: SocketAddr,
+    guard: ConnectionGuard,
+    /// One client per login, so that pooled connections are never shared between users.
+    clients: parking_lot::Mutex<std::collections::HashMap<Option<Login>, UpstreamClient<U>>>,
+}
+
+impl<U: Upstreams> ProxyConnection<U> {
+    fn new_shared(upstreams: Arc<U>, client_addr: SocketAddr, guard: ConnectionGuard) -> Arc<Self> {
+        Arc::new(ProxyConnection {
+            upstreams,
+            client_addr,
+            guard,
+            clients: Default::default(),
+        })
+    }
+
+    fn client_for(&self, login: Option<Login>, user: U::User) -> UpstreamClient<U> {
+        self.clients
+            .lock()
+            .entry(login)
+            .or_insert_with(|| {
+                let connector = UpstreamConnector {
+                    upstreams: self.upstreams.clone(),
+                    client_addr: self.client_addr,
+                    user,
+                    guard: self.guard.clone(),
+                };
+                hyper::Client::builder().build(connector)
+            })
+            .clone()
+    }
 }
-pub type SharedProxyServer = std::sync::Arc<ProxyServer>;
-impl ProxyServer {
-    fn new(addr: SocketAddr) -> ProxyServer {
-        let connector = http_client::SocksConnector::new(addr);
-        let proxy_client: http_client::SocksClient = hyper::Client::builder().build(connector);
-        ProxyServer {
-            addr,
-            client: proxy_client,
+
+type UpstreamClient<U> = hyper::Client<UpstreamConnector<U>, Body>;
+
+/// How much data is buffered between hyper and a connection that it opened through [`Upstreams`].
+const PIPE_BUFFER: usize = 65536;
+
+/// Opens hyper's connections through [`Upstreams`], relaying each one over an in-memory pipe.
+struct UpstreamConnector<U: Upstreams> {
+    upstreams: Arc<U>,
+    client_addr: SocketAddr,
+    user: U::User,
+    guard: ConnectionGuard,
+}
+
+impl<U: Upstreams> Clone for UpstreamConnector<U> {
+    fn clone(&self) -> Self {
+        UpstreamConnector {
+            upstreams: self.upstreams.clone(),
+            client_addr: self.client_addr,
+            user: self.user.clone(),
+            guard: self.guard.clone(),
+        }
+    }
+}
+
+impl<U: Upstreams> hyper::service::Service<Uri> for UpstreamConnector<U> {
+    type Error = OpenError;
+    type Future = BoxFuture<'static, Result<UpstreamStream, OpenError>>;
+    type Response = UpstreamStream;
+    fn poll_ready(&mut self, _cx: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let this = self.clone();
+        async move {
+            let addr = host_addr(&dst).ok_or_else(|| OpenError {
+                status: StatusCode::BAD_REQUEST,
+                message: "URI must be a valid Address".into(),
+            })?;
+            let conn = this.upstreams.open(&addr).await?;
+            let (stream, pipe) = tokio::io::duplex(PIPE_BUFFER);
+            this.upstreams.relay(
+                async_compat::Compat::new(pipe),
+                this.client_addr,
+                &addr,
+                conn,
+                this.user,
+                this.guard,
+            );
+            Ok(UpstreamStream(stream))
         }
+        .boxed()
+    }
+}
+
+/// hyper's end of a connection opened through [`Upstreams`].
+struct UpstreamStream(tokio::io::DuplexStream);
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl tokio::io::AsyncRead for UpstreamStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl tokio::io::AsyncWrite for UpstreamStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
     }
-    fn new_shared(addr: SocketAddr) -> SharedProxyServer {
-        std::sync::Arc::new(ProxyServer::new(addr))
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
     }
 }
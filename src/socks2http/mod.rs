@@ -1,14 +1,100 @@
 mod address;
-mod consts;
-mod http_client;
 mod http_local;
-mod socks5;
-use std::net::SocketAddr;
+use std::{fmt, net::SocketAddr, sync::Arc};
 
-pub async fn run_tokio(
+use async_trait::async_trait;
+
+pub use address::Address;
+pub use http_local::ConnectionGuard;
+
+/// What the HTTP proxy does with a client that just connected.
+pub enum ClientVerdict<G> {
+    /// Serves it, holding on to the guard until the connection and every CONNECT tunnel opened on it are closed.
+    Serve(G),
+    /// Answers every request with 403 Forbidden.
+    Forbid,
+    /// Closes the connection right away.
+    Close,
+}
+
+/// A username and password from a `Proxy-Authorization: Basic` header.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Login {
+    pub username: String,
+    pub password: String,
+}
+
+/// Why a connection to a destination could not be opened, as the status code and message that the client is answered with.
+#[derive(Debug)]
+pub struct OpenError {
+    pub status: http::StatusCode,
+    pub message: String,
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.status)
+    }
+}
+
+impl std::error::Error for OpenError {}
+
+/// Where the HTTP proxy sends the requests it serves.
+#[async_trait]
+pub trait Upstreams: Send + Sync + 'static {
+    /// Whoever a client's traffic is counted toward.
+    type User: Clone + Send + Sync + 'static;
+    /// A connection opened to a destination.
+    type Conn: Send + 'static;
+
+    /// Checks the login a client sent, if any. Returns `None` when the client has to log in, which it is told with 407 Proxy Authentication Required.
+    fn login(&self, login: Option<&Login>) -> Option<Self::User>;
+
+    /// Opens a connection to a destination.
+    async fn open(&self, addr: &Address) -> Result<Self::Conn, OpenError>;
+
+    /// Relays a client over a connection opened by [`Upstreams::open`] in the background, holding on to `guard` until it is done.
+    fn relay<S>(
+        &self,
+        client: S,
+        client_addr: SocketAddr,
+        addr: &Address,
+        conn: Self::Conn,
+        user: Self::User,
+        guard: ConnectionGuard,
+    ) where
+        S: futures_util::AsyncRead + futures_util::AsyncWrite + Send + Unpin + 'static;
+}
+
+/// Runs an HTTP proxy sending its requests to `upstreams`. `verdict` decides what happens to each client as it connects.
+pub async fn run_tokio<U: Upstreams, G: Send + Sync + 'static>(
     local_listen_addr: SocketAddr,
-    proxy_address: SocketAddr,
+    upstreams: Arc<U>,
+    verdict: impl Fn(SocketAddr) -> ClientVerdict<G>,
 ) -> anyhow::Result<()> {
-    http_local::run(local_listen_addr, proxy_address).await?;
+    http_local::run(local_listen_addr, upstreams, verdict).await?;
+    Ok(())
+}
+
+/// Serves HTTP proxy requests on one already-accepted connection, sending them to `upstreams`. `guard` is held until the connection and every request relayed for it are done.
+pub async fn serve_http<U: Upstreams>(
+    stream: smol::net::TcpStream,
+    client_addr: SocketAddr,
+    upstreams: Arc<U>,
+    guard: ConnectionGuard,
+) -> anyhow::Result<()> {
+    http_local::serve_connection(
+        async_compat::Compat::new(stream),
+        client_addr,
+        upstreams,
+        guard,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Answers every request on one already-accepted connection with 403 Forbidden, for clients that may not use the proxy.
+pub async fn serve_forbidden(stream: smol::net::TcpStream) -> anyhow::Result<()> {
+    http_local::serve_forbidden(async_compat::Compat::new(stream)).await?;
     Ok(())
 }
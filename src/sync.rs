@@ -1,5 +1,5 @@
 use anyhow::Context;
-use geph4_protocol::binder::protocol::{Credentials, Level, UserInfoV2};
+use geph4_protocol::binder::protocol::{Level, UserInfoV2};
 
 use geph5_broker_protocol::BrokerClient;
 use itertools::Itertools;
@@ -11,9 +11,10 @@ use stdcode::StdcodeSerializeExt;
 
 use structopt::StructOpt;
 
-use crate::config::{AuthOpt, CommonOpt, GEPH5_CONFIG_TEMPLATE};
+use crate::config::{AuthOpt, CommonOpt};
 
 #[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct SyncOpt {
     #[structopt(flatten)]
     pub common: CommonOpt,
@@ -34,14 +35,9 @@ pub async fn main_sync(opt: SyncOpt) -> anyhow::Result<()> {
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 pub async fn sync_json(opt: SyncOpt) -> anyhow::Result<String> {
     log::info!("SYNC getting conninfo store");
+    let credentials = opt.auth.geph5_credential()?;
 
-    let broker_transport = BrokerClient::from(
-        GEPH5_CONFIG_TEMPLATE
-            .broker
-            .as_ref()
-            .unwrap()
-            .rpc_transport(),
-    );
+    let broker_transport = BrokerClient::from(opt.common.broker_transport());
 
     let timeout_duration = Duration::from_secs(15);
     let result = (async {
@@ -73,18 +69,13 @@ pub async fn sync_json(opt: SyncOpt) -> anyhow::Result<String> {
             })
             .collect_vec();
 
-        let credentials = match &opt.auth.auth_kind {
-            Some(crate::config::AuthKind::AuthPassword { username, password }) => {
-                geph5_broker_protocol::Credential::LegacyUsernamePassword { username: username.clone(), password: password.clone() }
-            }
-            _ => todo!(),
-        };
         let user_cache_key = hex::encode(blake3::hash(&opt.auth.stdcode()).as_bytes());
         let token_path = opt.auth.credential_cache.join(format!("{user_cache_key}-sync_auth_token"));
         let auth_token = if let Ok(val) = smol::fs::read_to_string(&token_path).await {
             val
         } else {
             let auth_token = broker_transport.get_auth_token(credentials).await??;
+            smol::fs::create_dir_all(&opt.auth.credential_cache).await?;
             smol::fs::write(&token_path, &auth_token).await?;
             auth_token
         };